@@ -0,0 +1,399 @@
+use crate::executor::executor_for;
+use crate::service::{execute_task, task_invocation, wait_for_signal};
+use crate::settings::DirSettings;
+use crate::task::TaskStatus;
+use crate::workers::{AgentJob, Registration, StatusReport, WorkerDir, parse_labels};
+use anyhow::Context;
+use reqwest::{StatusCode, header};
+use std::collections::HashSet;
+use std::env;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+
+/// How often the output of a running task is sent to the server.
+const LOG_UPLOAD_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait before retrying after the server could not be reached.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Settings of `remote-task agent`, read from the environment.
+struct Agent {
+    client: reqwest::Client,
+    /// `AGENT_SERVER`: base URL of the central server.
+    server: String,
+    /// `AGENT_NAME`: defaults to the host name.
+    name: String,
+    /// `AGENT_LABELS`: comma separated labels advertised to the server.
+    labels: Vec<String>,
+    /// `AGENT_SLOTS`: tasks run at the same time, defaults to 1.
+    slots: usize,
+    work_dirs: Vec<PathBuf>,
+    output_dir: PathBuf,
+    logs_dir: PathBuf,
+}
+
+enum PollError {
+    NotRegistered,
+    Other(anyhow::Error),
+}
+
+impl Agent {
+    fn from_env(work_dirs: Vec<PathBuf>, output_dir: PathBuf) -> anyhow::Result<Self> {
+        let server = env::var("AGENT_SERVER")
+            .context("AGENT_SERVER must be set to the URL of the server")?
+            .trim_end_matches('/')
+            .to_string();
+        // Tokens from `remote-task generate-agent-token`, named after the agent.
+        let token = match env::var("AGENT_TOKEN") {
+            Ok(token) => token,
+            Err(_) => std::fs::read_to_string("token.txt")
+                .context("AGENT_TOKEN is not set and token.txt cannot be read")?,
+        };
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            header::HeaderValue::from_str(&format!("token={}", token.trim()))?,
+        );
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()?;
+        let name = env::var("AGENT_NAME")
+            .or_else(|_| env::var("HOSTNAME"))
+            .or_else(|_| env::var("COMPUTERNAME"))
+            .unwrap_or("agent".to_string());
+        let labels = parse_labels(&env::var("AGENT_LABELS").unwrap_or_default());
+        let slots = env::var("AGENT_SLOTS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&slots| slots > 0)
+            .unwrap_or(1);
+        let logs_dir = env::temp_dir().join(format!("remote-task-agent-{name}"));
+        std::fs::create_dir_all(&logs_dir)?;
+        Ok(Agent {
+            client,
+            server,
+            name,
+            labels,
+            slots,
+            work_dirs,
+            output_dir,
+            logs_dir,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.server, path)
+    }
+
+    async fn register(&self, running: Vec<i32>) -> anyhow::Result<()> {
+        let mut dirs = Vec::new();
+        for dir in &self.work_dirs {
+            let settings = DirSettings::load(dir)?;
+            let recipes = executor_for(&settings.executor)
+                .list(dir)
+                .unwrap_or_else(|err| {
+                    warn!("Cannot list recipes of {}: {:#}", dir.display(), err);
+                    Vec::new()
+                });
+            dirs.push(WorkerDir {
+                path: dir.clone(),
+                recipes,
+                labels: settings.labels,
+            });
+        }
+        let registration = Registration {
+            name: self.name.clone(),
+            labels: self.labels.clone(),
+            dirs,
+            slots: self.slots,
+            running,
+        };
+        self.client
+            .post(self.url("/agent/register"))
+            .json(&registration)
+            .send()
+            .await?
+            .error_for_status()?;
+        info!("Registered with {} as {}", self.server, self.name);
+        Ok(())
+    }
+
+    async fn poll(&self) -> Result<Option<AgentJob>, PollError> {
+        let response = self
+            .client
+            .get(self.url("/agent/poll"))
+            .query(&[("name", &self.name)])
+            .send()
+            .await
+            .map_err(|err| PollError::Other(err.into()))?;
+        match response.status() {
+            StatusCode::NO_CONTENT => Ok(None),
+            StatusCode::NOT_FOUND => Err(PollError::NotRegistered),
+            _ => {
+                let response = response
+                    .error_for_status()
+                    .map_err(|err| PollError::Other(err.into()))?;
+                let job = response
+                    .json()
+                    .await
+                    .map_err(|err| PollError::Other(err.into()))?;
+                Ok(Some(job))
+            }
+        }
+    }
+
+    /// Sends the log written since `offset`, which is advanced on success.
+    async fn upload_log(&self, id: i32, log_file: &Path, offset: &mut u64) {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+        let mut chunk = Vec::new();
+        let read = async {
+            let mut file = tokio::fs::File::open(log_file).await?;
+            file.seek(SeekFrom::Start(*offset)).await?;
+            file.read_to_end(&mut chunk).await
+        };
+        if read.await.is_err() || chunk.is_empty() {
+            return;
+        }
+        let len = chunk.len() as u64;
+        let result = self
+            .client
+            .post(self.url(&format!("/agent/task/{id}/log")))
+            .query(&[("name", &self.name)])
+            .body(chunk)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => *offset += len,
+            Err(err) => warn!("Failed to upload log of task {}: {}", id, err),
+        }
+    }
+
+    async fn upload_artifact(&self, id: i32, path: &Path) -> anyhow::Result<()> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        self.client
+            .put(self.url(&format!("/agent/task/{id}/artifact")))
+            .query(&[("name", &self.name)])
+            .body(file)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Confirms that the agent received a task, which the server sends again until then.
+    async fn confirm(&self, id: i32) {
+        let result = self
+            .client
+            .post(self.url(&format!("/agent/task/{id}/status")))
+            .query(&[("name", &self.name)])
+            .json(&StatusReport {
+                status: TaskStatus::Running,
+            })
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = result {
+            warn!("Failed to confirm task {}: {}", id, err);
+        }
+    }
+
+    async fn report(&self, id: i32, status: TaskStatus) {
+        // The server only learns the outcome from this report, so keep trying.
+        for _ in 0..10 {
+            let result = self
+                .client
+                .post(self.url(&format!("/agent/task/{id}/status")))
+                .query(&[("name", &self.name)])
+                .json(&StatusReport { status })
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => return,
+                Err(err) if err.status().is_some_and(|s| s.is_client_error()) => {
+                    warn!("Server rejected status of task {}: {}", id, err);
+                    return;
+                }
+                Err(err) => {
+                    warn!("Failed to report status of task {}: {}", id, err);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    async fn execute(&self, job: &AgentJob, terminate: watch::Receiver<bool>) -> std::io::Result<()> {
+        let task = &job.task;
+        let work_dir = PathBuf::from(&task.dir);
+        let log_file = self.logs_dir.join(format!("{}.log", task.id));
+        let mut offset = 0;
+        // Only directories the agent advertised, whatever the server asks for.
+        let allowed = if self.work_dirs.contains(&work_dir) {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "{} is not a work directory of this agent",
+                work_dir.display()
+            ))
+        };
+        let prepared = allowed.and_then(|_| DirSettings::load(&work_dir)).and_then(|settings| {
+            let mut invocation =
+                task_invocation(&settings, &work_dir, &task.command, job.env.clone())?;
+            invocation.secrets = job.secrets.clone();
+            Ok((settings, invocation))
+        });
+        let (settings, invocation) = match prepared {
+            Ok(prepared) => prepared,
+            Err(err) => {
+                let message = format!("{:#}", err);
+                tokio::fs::write(&log_file, &message).await?;
+                self.upload_log(task.id, &log_file, &mut offset).await;
+                return Err(std::io::Error::other(message));
+            }
+        };
+        let run = execute_task(
+            &settings,
+            invocation,
+            task,
+            &work_dir,
+            &self.output_dir,
+            &log_file,
+            terminate,
+        );
+        tokio::pin!(run);
+        let result = loop {
+            tokio::select! {
+                result = &mut run => break result,
+                _ = tokio::time::sleep(LOG_UPLOAD_INTERVAL) => {
+                    self.upload_log(task.id, &log_file, &mut offset).await;
+                }
+            }
+        };
+        self.upload_log(task.id, &log_file, &mut offset).await;
+        if result.is_ok()
+            && let Some(output) = &task.output
+        {
+            self.upload_artifact(task.id, &self.output_dir.join(output))
+                .await
+                .map_err(|err| std::io::Error::other(format!("{:#}", err)))?;
+        }
+        let _ = tokio::fs::remove_file(&log_file).await;
+        result
+    }
+
+    async fn run_job(self: Arc<Self>, job: AgentJob, terminate: watch::Receiver<bool>) -> i32 {
+        let id = job.task.id;
+        info!("Running task: {}", id);
+        let status = match self.execute(&job, terminate).await {
+            Ok(_) => {
+                info!("Task {} completed successfully", id);
+                TaskStatus::Success
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {
+                info!("Task {} interrupted", id);
+                TaskStatus::Interrupted
+            }
+            Err(err) => {
+                error!("Task {} failed: {}", id, err);
+                TaskStatus::Failed
+            }
+        };
+        self.report(id, status).await;
+        id
+    }
+}
+
+/// Runs `remote-task agent`: registers with the server and runs the tasks it dispatches.
+pub async fn run_agent(work_dirs: Vec<PathBuf>, output_dir: PathBuf) -> anyhow::Result<()> {
+    let agent = Arc::new(Agent::from_env(work_dirs, output_dir)?);
+    let (terminate, _) = watch::channel(false);
+    let mut running = JoinSet::new();
+    let mut active = HashSet::new();
+    let mut registered = false;
+    let work = async {
+        loop {
+            while let Some(result) = running.try_join_next() {
+                if let Ok(id) = result {
+                    active.remove(&id);
+                }
+            }
+            if !registered {
+                match agent.register(active.iter().copied().collect()).await {
+                    Ok(_) => registered = true,
+                    Err(err) => {
+                        warn!("Failed to register with {}: {:#}", agent.server, err);
+                        tokio::time::sleep(RETRY_DELAY).await;
+                        continue;
+                    }
+                }
+            }
+            match agent.poll().await {
+                Ok(Some(job)) => {
+                    agent.confirm(job.task.id).await;
+                    // A task whose confirmation was lost comes again while it runs.
+                    if active.insert(job.task.id) {
+                        running.spawn(agent.clone().run_job(job, terminate.subscribe()));
+                    }
+                }
+                Ok(None) => {}
+                Err(PollError::NotRegistered) => registered = false,
+                Err(PollError::Other(err)) => {
+                    warn!("Failed to poll {}: {:#}", agent.server, err);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    };
+    tokio::select! {
+        _ = work => {}
+        _ = wait_for_signal() => info!("Stopping agent..."),
+    }
+    let _ = terminate.send(true);
+    while running.join_next().await.is_some() {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task;
+
+    #[tokio::test]
+    async fn refuses_directories_it_did_not_advertise() {
+        let dir = env::temp_dir().join(format!("remote-task-agent-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("work")).unwrap();
+        let agent = Agent {
+            client: reqwest::Client::new(),
+            // Nothing listens there, uploads fail without stopping the task.
+            server: "http://127.0.0.1:1".to_string(),
+            name: "a1".to_string(),
+            labels: Vec::new(),
+            slots: 1,
+            work_dirs: vec![dir.join("work")],
+            output_dir: dir.clone(),
+            logs_dir: dir.clone(),
+        };
+        let mut task = task::Model::for_test(3);
+        task.dir = dir.to_string_lossy().to_string();
+        let job = AgentJob {
+            task,
+            env: Vec::new(),
+            secrets: Vec::new(),
+        };
+        let (_terminate, terminated) = watch::channel(false);
+
+        let err = agent.execute(&job, terminated).await.unwrap_err();
+        assert_ne!(err.kind(), std::io::ErrorKind::Interrupted);
+        let message = format!("{} is not a work directory of this agent", dir.display());
+        assert_eq!(err.to_string(), message);
+        assert_eq!(std::fs::read_to_string(dir.join("3.log")).unwrap(), message);
+    }
+}
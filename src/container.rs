@@ -0,0 +1,264 @@
+use crate::executor::Invocation;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use tracing::warn;
+
+/// Runs the tasks of a work directory inside a container image.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ContainerSettings {
+    /// Container runtime CLI, like `docker` or `podman`, defaults to `docker`.
+    pub runtime: Option<PathBuf>,
+    pub image: String,
+    /// Other volumes, in the `host:container[:options]` form of `--volume`.
+    pub volumes: Vec<String>,
+    /// Extra flags of the `run` command, like `["--network", "none"]`.
+    pub args: Vec<String>,
+}
+
+/// Name of the container of one run of a task. Runs of the same task get different names,
+/// so a container left behind by an earlier run does not block the next one.
+pub fn container_name(task_id: i32) -> String {
+    let run = OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+    format!("remote-task-{task_id}-{run}")
+}
+
+impl ContainerSettings {
+    fn runtime(&self) -> PathBuf {
+        self.runtime.clone().unwrap_or(PathBuf::from("docker"))
+    }
+
+    /// Wraps the invocation of a task into `<runtime> run`, mounting the work and output
+    /// directories at the same paths so the executor arguments stay valid.
+    pub fn wrap(
+        &self,
+        invocation: Invocation,
+        name: &str,
+        work_dir: &Path,
+        output_dir: &Path,
+    ) -> Invocation {
+        let mut args = vec![
+            "run".to_string(),
+            "--rm".to_string(),
+            "--init".to_string(),
+            "--name".to_string(),
+            name.to_string(),
+        ];
+        let mut volumes = vec![work_dir, output_dir]
+            .into_iter()
+            .map(|dir| {
+                let dir = dir.to_string_lossy();
+                format!("{dir}:{dir}")
+            })
+            .collect::<Vec<_>>();
+        volumes.dedup();
+        for volume in volumes.iter().chain(&self.volumes) {
+            args.extend(["--volume".to_string(), volume.clone()]);
+        }
+        args.extend([
+            "--workdir".to_string(),
+            work_dir.to_string_lossy().to_string(),
+        ]);
+        // Only the names go on the command line, the runtime takes the values from its
+        // own environment so secrets do not show up in the process list.
+        for (key, _) in &invocation.env {
+            args.extend(["--env".to_string(), key.clone()]);
+        }
+        args.extend(self.args.iter().cloned());
+        args.push(self.image.clone());
+        args.push(invocation.program.to_string_lossy().to_string());
+        args.extend(invocation.args);
+        Invocation {
+            program: self.runtime(),
+            args,
+            ..invocation
+        }
+    }
+
+    /// Kills the container of an interrupted task, killing the runtime CLI does not stop it.
+    pub async fn kill(&self, name: &str) {
+        let result = tokio::process::Command::new(self.runtime())
+            .args(["kill", name])
+            .output()
+            .await;
+        match result {
+            Ok(output) if !output.status.success() => warn!(
+                "Failed to kill container {}: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(err) => warn!("Failed to kill container {}: {}", name, err),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::service::execute_task;
+    use crate::settings::DirSettings;
+    use crate::task;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::sync::watch;
+
+    /// Runtime which prints its arguments and the value of `TOKEN` instead of running
+    /// a container, and records `kill` calls next to itself.
+    const FAKE_RUNTIME: &str = r#"#!/bin/sh
+if [ "$1" = kill ]; then
+    echo "$@" >> "$(dirname "$0")/kills"
+    exit 0
+fi
+for arg in "$@"; do echo "arg:$arg"; done
+echo "env:TOKEN=$TOKEN"
+if [ -n "$SLEEP" ]; then exec sleep "$SLEEP"; fi
+"#;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "remote-task-container-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("work")).unwrap();
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        let runtime = dir.join("docker");
+        std::fs::write(&runtime, FAKE_RUNTIME).unwrap();
+        std::fs::set_permissions(&runtime, std::fs::Permissions::from_mode(0o755)).unwrap();
+        dir
+    }
+
+    fn settings(dir: &Path) -> DirSettings {
+        DirSettings {
+            container: Some(ContainerSettings {
+                runtime: Some(dir.join("docker")),
+                image: "alpine:3".to_string(),
+                volumes: vec!["/cache:/cache:ro".to_string()],
+                args: vec!["--network".to_string(), "none".to_string()],
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn invocation(env: &[(&str, &str)]) -> Invocation {
+        let mut invocation = Invocation::new("just").args(["build", "--release"]);
+        invocation.env = env
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        invocation
+    }
+
+    #[tokio::test]
+    async fn runs_the_task_in_the_image() {
+        let dir = test_dir("run");
+        let (work_dir, output_dir) = (dir.join("work"), dir.join("out"));
+        let log = dir.join("task.log");
+        let (_terminate, terminated) = watch::channel(false);
+        execute_task(
+            &settings(&dir),
+            invocation(&[("TOKEN", "t0ken")]),
+            &task::Model::for_test(7),
+            &work_dir,
+            &output_dir,
+            &log,
+            terminated,
+        )
+        .await
+        .unwrap();
+
+        let log = std::fs::read_to_string(log).unwrap();
+        let args = log
+            .lines()
+            .filter_map(|line| line.strip_prefix("arg:"))
+            .collect::<Vec<_>>();
+        let (work_dir, output_dir) = (work_dir.to_str().unwrap(), output_dir.to_str().unwrap());
+        assert_eq!(&args[..4], ["run", "--rm", "--init", "--name"]);
+        assert!(args[4].starts_with("remote-task-7-"), "{}", args[4]);
+        assert_eq!(
+            &args[5..],
+            [
+                "--volume",
+                &format!("{work_dir}:{work_dir}"),
+                "--volume",
+                &format!("{output_dir}:{output_dir}"),
+                "--volume",
+                "/cache:/cache:ro",
+                "--workdir",
+                work_dir,
+                "--env",
+                "TOKEN",
+                "--network",
+                "none",
+                "alpine:3",
+                "just",
+                "build",
+                "--release",
+            ]
+        );
+        // The value reaches the runtime through its environment only.
+        assert!(log.contains("env:TOKEN=t0ken"), "{log}");
+        assert!(!args.iter().any(|arg| arg.contains("t0ken")));
+        assert!(!dir.join("kills").exists());
+    }
+
+    #[tokio::test]
+    async fn kills_the_container_of_an_interrupted_task() {
+        let dir = test_dir("kill");
+        let log = dir.join("task.log");
+        let (settings, task) = (settings(&dir), task::Model::for_test(8));
+        let (work_dir, output_dir) = (dir.join("work"), dir.join("out"));
+        let (terminate, terminated) = watch::channel(false);
+        let run = execute_task(
+            &settings,
+            invocation(&[("SLEEP", "30")]),
+            &task,
+            &work_dir,
+            &output_dir,
+            &log,
+            terminated,
+        );
+        let stop = async {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            terminate.send(true).unwrap();
+        };
+        let (result, ()) = tokio::join!(run, stop);
+        assert_eq!(
+            result.unwrap_err().kind(),
+            std::io::ErrorKind::Interrupted
+        );
+
+        let log = std::fs::read_to_string(log).unwrap();
+        let name = log
+            .lines()
+            .skip_while(|line| *line != "arg:--name")
+            .nth(1)
+            .and_then(|line| line.strip_prefix("arg:"))
+            .unwrap();
+        let kills = std::fs::read_to_string(dir.join("kills")).unwrap();
+        assert_eq!(kills, format!("kill {name}\n"));
+    }
+
+    #[test]
+    fn runs_get_different_names() {
+        let first = container_name(3);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert_ne!(first, container_name(3));
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let dir = test_dir("settings");
+        let load = |settings: &str| {
+            std::fs::write(dir.join(crate::settings::SETTINGS_FILE), settings).unwrap();
+            DirSettings::load(&dir).map_err(|err| format!("{err:#}"))
+        };
+        let err = load(r#"{"container": {"image": " "}}"#).unwrap_err();
+        assert!(err.contains("container.image must not be empty"), "{err}");
+        let err = load(r#"{"container": {"image": "alpine"}, "process": {"sandbox": {}}}"#)
+            .unwrap_err();
+        assert!(err.contains("cannot be used together"), "{err}");
+        assert!(load(r#"{"container": {"image": "alpine"}}"#).is_ok());
+    }
+}
@@ -0,0 +1,254 @@
+use crate::settings::{DirSettings, ExecutorKind, ExecutorSettings};
+use anyhow::{Context, bail};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::warn;
+
+/// Program and arguments which run one task.
+#[derive(Clone, Debug)]
+pub struct Invocation {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    /// Environment variables set on top of the server environment.
+    pub env: Vec<(String, String)>,
+    /// Values of `env` to redact from the log.
+    pub secrets: Vec<String>,
+}
+
+impl Invocation {
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        Invocation {
+            program: program.into(),
+            args: Vec::new(),
+            env: Vec::new(),
+            secrets: Vec::new(),
+        }
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+}
+
+/// A tool which knows the targets of a work directory and how to run them.
+pub trait Executor: Send + Sync {
+    /// Lists the targets available in the work directory.
+    fn list(&self, dir: &Path) -> anyhow::Result<Vec<String>>;
+
+    /// Builds the invocation of a command, whose first word is the target.
+    fn invocation(&self, dir: &Path, command: &str) -> anyhow::Result<Invocation>;
+
+    /// Verifies that the tool is installed and the directory is usable.
+    fn check(&self, dir: &Path) -> anyhow::Result<()> {
+        self.list(dir).map(|_| ())
+    }
+}
+
+pub fn executor_for(settings: &ExecutorSettings) -> Box<dyn Executor> {
+    match settings.kind {
+        ExecutorKind::Just => Box::new(JustExecutor {
+            binary: settings.binary.clone().unwrap_or(PathBuf::from("just")),
+            args: settings.args.clone(),
+            justfile: settings.file.clone(),
+        }),
+        ExecutorKind::Make => Box::new(MakeExecutor {
+            binary: settings.binary.clone().unwrap_or(PathBuf::from("make")),
+            args: settings.args.clone(),
+            makefile: settings.file.clone(),
+        }),
+        ExecutorKind::Script => Box::new(ScriptExecutor {
+            scripts: settings.scripts.clone().unwrap_or(PathBuf::from("scripts")),
+        }),
+    }
+}
+
+/// Checks the executor of every work directory, returning the errors by directory.
+pub fn check_executors(settings: &HashMap<PathBuf, DirSettings>) -> HashMap<PathBuf, String> {
+    let mut errors = HashMap::new();
+    for (dir, settings) in settings {
+        if let Err(err) = executor_for(&settings.executor).check(dir) {
+            let message = format!("{:#}", err);
+            warn!(
+                "Executor {:?} is not usable in {}: {}",
+                settings.executor.kind,
+                dir.display(),
+                message
+            );
+            errors.insert(dir.clone(), message);
+        }
+    }
+    errors
+}
+
+fn run_for_output(command: &mut Command) -> anyhow::Result<String> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command
+        .output()
+        .with_context(|| format!("failed to run {program}"))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim())
+    }
+}
+
+fn split_command(command: &str) -> Vec<&str> {
+    command.split(' ').filter(|item| !item.is_empty()).collect()
+}
+
+pub struct JustExecutor {
+    pub binary: PathBuf,
+    pub args: Vec<String>,
+    pub justfile: Option<PathBuf>,
+}
+
+impl JustExecutor {
+    fn base_args(&self, dir: &Path) -> Vec<String> {
+        let mut args = self.args.clone();
+        if let Some(justfile) = &self.justfile {
+            args.push("--justfile".to_string());
+            args.push(dir.join(justfile).to_string_lossy().to_string());
+            args.push("--working-directory".to_string());
+            args.push(dir.to_string_lossy().to_string());
+        }
+        args
+    }
+}
+
+impl Executor for JustExecutor {
+    fn list(&self, dir: &Path) -> anyhow::Result<Vec<String>> {
+        let output = run_for_output(
+            Command::new(&self.binary)
+                .current_dir(dir)
+                .args(self.base_args(dir))
+                .arg("--list"),
+        )?;
+        Ok(output
+            .lines()
+            .skip(1) // skip "Available recipes:"
+            .map(|line| line.trim().to_string())
+            .collect())
+    }
+
+    fn invocation(&self, dir: &Path, command: &str) -> anyhow::Result<Invocation> {
+        Ok(Invocation::new(&self.binary)
+            .args(self.base_args(dir))
+            .args(split_command(command)))
+    }
+}
+
+pub struct MakeExecutor {
+    pub binary: PathBuf,
+    pub args: Vec<String>,
+    pub makefile: Option<PathBuf>,
+}
+
+impl MakeExecutor {
+    fn base_args(&self, dir: &Path) -> Vec<String> {
+        let mut args = self.args.clone();
+        if let Some(makefile) = &self.makefile {
+            args.push("-f".to_string());
+            args.push(dir.join(makefile).to_string_lossy().to_string());
+        }
+        args
+    }
+}
+
+impl Executor for MakeExecutor {
+    fn list(&self, dir: &Path) -> anyhow::Result<Vec<String>> {
+        // Print the database without running anything, then pick the explicit targets.
+        let output = Command::new(&self.binary)
+            .current_dir(dir)
+            .args(self.base_args(dir))
+            .args(["-pRrq", ":"])
+            .output()
+            .with_context(|| format!("failed to run {}", self.binary.display()))?;
+        let database = String::from_utf8_lossy(&output.stdout);
+        let mut targets = Vec::new();
+        let mut previous = "";
+        for line in database.lines() {
+            let is_target = previous != "# Not a target:"
+                && line.starts_with(|c: char| c.is_ascii_alphanumeric())
+                && !line.contains(['$', '%', '=', '\t']);
+            if is_target && let Some((names, _)) = line.split_once(':') {
+                targets.extend(names.split_whitespace().map(str::to_string));
+            }
+            previous = line;
+        }
+        if targets.is_empty() && !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        targets.retain(|target| target != "Makefile" && target != "makefile");
+        targets.sort();
+        targets.dedup();
+        Ok(targets)
+    }
+
+    fn invocation(&self, dir: &Path, command: &str) -> anyhow::Result<Invocation> {
+        Ok(Invocation::new(&self.binary)
+            .args(self.base_args(dir))
+            .args(split_command(command)))
+    }
+}
+
+/// Runs the scripts in a directory, the target is the script name without extension.
+pub struct ScriptExecutor {
+    pub scripts: PathBuf,
+}
+
+const SCRIPT_EXTENSIONS: [&str; 5] = ["sh", "bash", "bat", "cmd", "ps1"];
+
+impl ScriptExecutor {
+    fn scripts(&self, dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let scripts_dir = dir.join(&self.scripts);
+        let entries = std::fs::read_dir(&scripts_dir)
+            .with_context(|| format!("failed to read {}", scripts_dir.display()))?;
+        let mut scripts = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            if path.is_file() && SCRIPT_EXTENSIONS.contains(&extension) {
+                scripts.push(path);
+            }
+        }
+        scripts.sort();
+        Ok(scripts)
+    }
+}
+
+impl Executor for ScriptExecutor {
+    fn list(&self, dir: &Path) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .scripts(dir)?
+            .iter()
+            .filter_map(|path| path.file_stem()?.to_str().map(str::to_string))
+            .collect())
+    }
+
+    fn invocation(&self, dir: &Path, command: &str) -> anyhow::Result<Invocation> {
+        let items = split_command(command);
+        let Some((name, args)) = items.split_first() else {
+            bail!("empty command");
+        };
+        let script = self
+            .scripts(dir)?
+            .into_iter()
+            .find(|path| path.file_stem().is_some_and(|stem| stem == *name))
+            .with_context(|| format!("script {name} not found"))?;
+        let invocation = match script.extension().and_then(|e| e.to_str()) {
+            Some("bat" | "cmd") => Invocation::new("cmd").args(["/C".to_string()]),
+            Some("ps1") => Invocation::new("powershell").args(["-File".to_string()]),
+            Some("bash") => Invocation::new("bash"),
+            _ => Invocation::new("sh"),
+        };
+        Ok(invocation
+            .args([script.to_string_lossy().to_string()])
+            .args(args.iter().copied()))
+    }
+}
@@ -0,0 +1,110 @@
+use crate::executor::executor_for;
+use crate::settings::DirSettings;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Time a single check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Result of one readiness check.
+#[derive(Clone, Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    /// Directory the check is about, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub message: String,
+}
+
+impl Check {
+    pub fn new(name: &'static str, target: Option<&Path>, result: Result<String, String>) -> Self {
+        let ok = result.is_ok();
+        Check {
+            name,
+            target: target.map(Path::to_path_buf),
+            ok,
+            message: result.unwrap_or_else(|err| err),
+        }
+    }
+}
+
+/// Runs a blocking check on the thread pool, failing it when it takes too long.
+async fn blocking<F>(check: F) -> Result<String, String>
+where
+    F: FnOnce() -> Result<String, String> + Send + 'static,
+{
+    match tokio::time::timeout(CHECK_TIMEOUT, tokio::task::spawn_blocking(check)).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err(format!("timed out after {}s", CHECK_TIMEOUT.as_secs())),
+    }
+}
+
+/// Whether the executor of a work directory is installed and can list its recipes.
+pub async fn executor(dir: &Path, settings: &DirSettings) -> Check {
+    let executor = settings.executor.clone();
+    let path = dir.to_path_buf();
+    let result = blocking(move || {
+        executor_for(&executor)
+            .check(&path)
+            .map(|_| String::new())
+            .map_err(|err| format!("{:#}", err))
+    })
+    .await;
+    Check::new("executor", Some(dir), result)
+}
+
+/// Whether a file can be created in the directory.
+pub async fn writable(dir: &Path) -> Check {
+    let path = dir.join(format!(".remote-task-ready-{}", std::process::id()));
+    let result = blocking(move || {
+        std::fs::write(&path, b"")
+            .and_then(|_| std::fs::remove_file(&path))
+            .map(|_| String::new())
+            .map_err(|err| err.to_string())
+    })
+    .await;
+    Check::new("writable", Some(dir), result)
+}
+
+/// Whether the file system of the directory has at least `min_mb` megabytes free.
+pub async fn disk_space(dir: &Path, min_mb: u64) -> Check {
+    let path = dir.to_path_buf();
+    let result = blocking(move || {
+        let Some(free) = free_space(&path).map_err(|err| err.to_string())? else {
+            return Ok("not checked on this platform".to_string());
+        };
+        let free = free / (1024 * 1024);
+        let message = format!("{free} MB free");
+        if free >= min_mb {
+            Ok(message)
+        } else {
+            Err(format!("{message}, less than {min_mb} MB"))
+        }
+    })
+    .await;
+    Check::new("disk_space", Some(dir), result)
+}
+
+#[cfg(unix)]
+fn free_space(path: &Path) -> std::io::Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The field types differ between platforms.
+    #[allow(clippy::useless_conversion)]
+    let free = u64::from(stat.f_bavail) * u64::from(stat.f_frsize);
+    Ok(Some(free))
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
@@ -0,0 +1,98 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Submission limits, a missing value means unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// `RATE_LIMIT_USER`: submissions per minute for one user.
+    pub user_per_minute: Option<usize>,
+    /// `RATE_LIMIT_IP`: submissions per minute from one IP address.
+    pub ip_per_minute: Option<usize>,
+    /// `MAX_PENDING_PER_USER`: pending tasks one user may have queued.
+    pub max_pending_per_user: Option<u64>,
+    /// `MAX_IDENTICAL_PENDING`: pending tasks with the same command and directory.
+    pub max_identical_pending: Option<u64>,
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(key: &str) -> Option<T> {
+            env::var(key).ok().and_then(|value| value.parse().ok())
+        }
+        Limits {
+            user_per_minute: var("RATE_LIMIT_USER"),
+            ip_per_minute: var("RATE_LIMIT_IP"),
+            max_pending_per_user: var("MAX_PENDING_PER_USER"),
+            max_identical_pending: var("MAX_IDENTICAL_PENDING"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Usage {
+    pub used: u64,
+    pub limit: Option<u64>,
+}
+
+/// Sliding window counter of submissions per key.
+pub struct RateLimiter {
+    pub limits: Limits,
+    hits: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> Self {
+        RateLimiter {
+            limits,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a hit for every key if none of them is over its limit,
+    /// otherwise returns how long to wait until the next hit is allowed.
+    pub fn acquire(&self, keys: &[(&str, Option<usize>)]) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+        hits.retain(|_, times| {
+            while times.front().is_some_and(|t| now.duration_since(*t) >= WINDOW) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        for (key, limit) in keys {
+            let Some(limit) = limit else { continue };
+            let times = hits.get(*key);
+            let count = times.map_or(0, |times| times.len());
+            if count >= *limit {
+                // Wait until enough of the recorded hits have left the window.
+                let wait = times
+                    .and_then(|times| times.get(count - limit))
+                    .map_or(WINDOW, |oldest| {
+                        WINDOW.saturating_sub(now.duration_since(*oldest))
+                    });
+                return Err(wait);
+            }
+        }
+        for (key, _) in keys {
+            hits.entry(key.to_string()).or_default().push_back(now);
+        }
+        Ok(())
+    }
+
+    /// Number of hits for the key within the last minute.
+    pub fn used(&self, key: &str) -> u64 {
+        let now = Instant::now();
+        let hits = self.hits.lock().unwrap();
+        hits.get(key).map_or(0, |times| {
+            times
+                .iter()
+                .filter(|t| now.duration_since(**t) < WINDOW)
+                .count() as u64
+        })
+    }
+}
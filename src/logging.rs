@@ -0,0 +1,347 @@
+use crate::task;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Target of spans which only add fields to log lines, they are not exported as traces.
+pub const CONTEXT_TARGET: &str = "remote_task::context";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Invalid LOG_FORMAT: {value}, expected text or json"
+            )),
+        }
+    }
+}
+
+/// Layer writing log lines in the format, colored only in text written to a terminal alone.
+pub fn layer<S>(
+    format: LogFormat,
+    writer: BoxMakeWriter,
+    ansi: bool,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Text => layer.with_ansi(ansi).boxed(),
+        LogFormat::Json => layer
+            .fmt_fields(JsonFields::new())
+            .event_format(FlatJson)
+            .boxed(),
+    }
+}
+
+/// Span adding the fields of a task to the lines logged while the runner handles it.
+pub fn task_span(task: &task::Model) -> tracing::Span {
+    tracing::info_span!(
+        target: CONTEXT_TARGET,
+        "task",
+        task_id = task.id,
+        recipe = task::recipe_of(&task.command),
+        user = %task.submitter,
+        dir = %task.dir,
+    )
+}
+
+/// Adds the fields of a task to the request being handled.
+pub fn record_task(task: &task::Model) {
+    let span = tracing::Span::current();
+    span.record("task_id", task.id);
+    span.record("recipe", task::recipe_of(&task.command));
+    span.record("dir", task.dir.as_str());
+}
+
+/// Formats each event as one JSON object, with the fields of the spans it happened in
+/// next to its own fields. Spans must be recorded with `JsonFields`.
+pub struct FlatJson;
+
+struct JsonVisitor<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for FlatJson
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let meta = event.metadata();
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let mut line = serde_json::Map::new();
+        if let Some(scope) = ctx.event_scope() {
+            let mut spans = Vec::new();
+            for span in scope.from_root() {
+                spans.push(span.name());
+                let extensions = span.extensions();
+                let Some(fields) = extensions.get::<FormattedFields<N>>() else {
+                    continue;
+                };
+                if let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(fields) {
+                    // Names of exported spans are not useful in log lines.
+                    line.extend(
+                        fields
+                            .into_iter()
+                            .filter(|(name, _)| !name.starts_with("otel.")),
+                    );
+                }
+            }
+            line.insert("span".to_string(), spans.join(":").into());
+        }
+        event.record(&mut JsonVisitor(&mut line));
+        // The common fields come first, so lines read well without a JSON viewer too.
+        let quote = |value: &str| serde_json::Value::from(value);
+        write!(
+            writer,
+            "{{\"timestamp\":{},\"level\":{},\"target\":{}",
+            quote(&timestamp),
+            quote(meta.level().as_str()),
+            quote(meta.target())
+        )?;
+        for (name, value) in line {
+            write!(writer, ",{}:{}", serde_json::Value::String(name), value)?;
+        }
+        writeln!(writer, "}}")
+    }
+}
+
+/// Log file which is rotated at midnight UTC and when it would grow over `max_size` bytes.
+/// Rotated files are named `<file>.<date>.<n>`, only the newest `max_files` are kept.
+/// A limit of zero turns off rotation by size or the removal of old files.
+pub struct RollingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+    date: time::Date,
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RollingFile {
+    pub fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let file = open_append(&path)?;
+        let meta = file.metadata()?;
+        // A file left from an earlier day is rotated with the first line of today.
+        let date = meta
+            .modified()
+            .map_or(OffsetDateTime::now_utc(), OffsetDateTime::from)
+            .date();
+        Ok(RollingFile {
+            path,
+            max_size,
+            max_files,
+            file,
+            size: meta.len(),
+            date,
+        })
+    }
+
+    fn dir(&self) -> &Path {
+        match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    }
+
+    fn file_name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    fn rotate(&mut self, today: time::Date) -> io::Result<()> {
+        self.file.flush()?;
+        // Numbered after the last file of the day, which may remain after older ones were removed.
+        let prefix = format!("{}.{}.", self.file_name(), self.date);
+        let last = fs::read_dir(self.dir())?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_prefix(&prefix)?.parse::<u32>().ok()
+            })
+            .max()
+            .unwrap_or(0);
+        fs::rename(&self.path, self.dir().join(format!("{prefix}{}", last + 1)))?;
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.date = today;
+        self.remove_old_files();
+        Ok(())
+    }
+
+    fn remove_old_files(&self) {
+        if self.max_files == 0 {
+            return;
+        }
+        let prefix = format!("{}.", self.file_name());
+        let Ok(entries) = fs::read_dir(self.dir()) else {
+            return;
+        };
+        let mut rotated = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| {
+                let modified = entry
+                    .metadata()
+                    .and_then(|meta| meta.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                (modified, entry.path())
+            })
+            .collect::<Vec<_>>();
+        rotated.sort_by(|a, b| b.cmp(a));
+        for (_, path) in rotated.into_iter().skip(self.max_files) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let today = OffsetDateTime::now_utc().date();
+        let full = self.max_size > 0 && self.size + buf.len() as u64 > self.max_size;
+        if self.size > 0 && (today != self.date || full) {
+            self.rotate(today)?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "remote-task-logging-{}-{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest_files() {
+        let dir = test_dir("size");
+        let mut log = RollingFile::open(dir.join("server.log"), 10, 2).unwrap();
+        let today = OffsetDateTime::now_utc().date();
+        log.write_all(b"line 1\n").unwrap();
+        assert_eq!(files(&dir), ["server.log"]);
+        for line in 2..=5 {
+            // Modification times order the rotated files.
+            std::thread::sleep(Duration::from_millis(20));
+            log.write_all(format!("line {line}\n").as_bytes()).unwrap();
+        }
+        log.flush().unwrap();
+        // Lines 1 to 4 were rotated one by one, the oldest two were removed.
+        assert_eq!(
+            files(&dir),
+            ["server.log", &format!("server.log.{today}.3"), &format!("server.log.{today}.4")]
+        );
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read(&format!("server.log.{today}.3")), "line 3\n");
+        assert_eq!(read(&format!("server.log.{today}.4")), "line 4\n");
+        assert_eq!(read("server.log"), "line 5\n");
+    }
+
+    #[test]
+    fn rotates_at_midnight() {
+        let dir = test_dir("daily");
+        let mut log = RollingFile::open(dir.join("server.log"), 0, 0).unwrap();
+        log.write_all(b"yesterday\n").unwrap();
+        let yesterday = log.date.previous_day().unwrap();
+        log.date = yesterday;
+        log.write_all(b"today\n").unwrap();
+        log.write_all(b"still today\n").unwrap();
+        log.flush().unwrap();
+        let rotated = format!("server.log.{yesterday}.1");
+        assert_eq!(files(&dir), ["server.log", rotated.as_str()]);
+        assert_eq!(fs::read_to_string(dir.join(rotated)).unwrap(), "yesterday\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("server.log")).unwrap(),
+            "today\nstill today\n"
+        );
+    }
+}
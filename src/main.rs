@@ -3,18 +3,40 @@ use axum::{
     Router,
     http::header,
     middleware,
-    routing::{get, post},
+    routing::{get, post, put},
 };
+use axum_server::tls_rustls::RustlsConfig;
 use sea_orm::Database;
-use std::{env, sync::{Arc, RwLock}};
+use std::{env, net::SocketAddr, sync::{Arc, RwLock}, time::Duration};
 use tokio::sync::broadcast;
 use tower::ServiceBuilder;
 use tower_http::{ServiceBuilderExt, services::ServeDir};
 use tracing::*;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    Layer,
+    filter::filter_fn,
+    fmt::writer::{BoxMakeWriter, MakeWriterExt},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
 
+mod agent;
+mod container;
+mod executor;
+mod health;
+mod limits;
+mod logging;
+mod metrics;
+mod process;
+mod secret;
 mod service;
+mod settings;
+mod stats;
 mod task;
+mod telemetry;
+mod tls;
+mod webhooks;
+mod workers;
 use service::*;
 
 const PATH_LIST_SEP: char = if cfg!(target_os = "windows") { ';' } else { ':' };
@@ -23,44 +45,98 @@ const PATH_LIST_SEP: char = if cfg!(target_os = "windows") { ';' } else { ':' };
 async fn main() -> anyhow::Result<()> {
     dotenvy::dotenv().ok();
     // CLI: generate token and exit
-    let mut args = std::env::args();
-    if let Some(_) = args.next() {
-        if let Some(cmd) = args.next() {
-            if cmd == "generate-token" || cmd == "--generate-token" {
-                let user = args.next().unwrap_or_else(|| "user".to_string());
-                let days = args
-                    .next()
-                    .and_then(|s| s.parse::<i64>().ok())
-                    .unwrap_or(90);
-                generate_token(user, days);
-                return Ok(());
-            }
-        }
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(cmd) = args.first()
+        && (cmd == "generate-token" || cmd == "--generate-token" || cmd == "generate-agent-token")
+    {
+        let (role, default_name) = if cmd == "generate-agent-token" {
+            (AGENT_ROLE, "agent")
+        } else {
+            ("admin", "user")
+        };
+        let user = args.get(1).cloned().unwrap_or_else(|| default_name.to_string());
+        let days = args
+            .get(2)
+            .and_then(|s| s.parse::<i64>().ok())
+            .unwrap_or(90);
+        generate_token(user, role, days);
+        return Ok(());
     }
+    let insecure_no_auth = args.iter().any(|arg| arg == "--insecure-no-auth");
     let db_url = env::var("DATABASE_URL").unwrap_or("sqlite:./tasks.db?mode=rwc".to_string());
     let host = env::var("HOST").unwrap_or("127.0.0.1".to_string());
     let port = env::var("PORT").unwrap_or("5678".to_string());
-    let secret = env::var("APP_SECRET").unwrap_or("".to_string());
     let work_dir_env = env::var("WORK_DIR").unwrap_or("".to_string());
     let work_dirs: Vec<std::path::PathBuf> = work_dir_env
         .split(PATH_LIST_SEP)
         .filter(|s| !s.is_empty())
-        .map(|s| std::path::PathBuf::from(s))
+        .map(std::path::PathBuf::from)
         .collect();
     let work_dir = work_dirs.first().cloned().unwrap_or_else(|| env::current_dir().unwrap());
     let output_dir = env::var("OUTPUT_DIR")
-        .map(|dir| std::path::PathBuf::from(dir))
+        .map(std::path::PathBuf::from)
         .unwrap_or(work_dir.clone());
     let logs_dir = work_dir.join("logs");
     let server_url = format!("{host}:{port}");
 
+    let telemetry = telemetry::init().context("failed to set up OpenTelemetry")?;
+    let log_format = env::var("LOG_FORMAT")
+        .map_or(Ok(logging::LogFormat::Text), |value| value.parse())
+        .map_err(anyhow::Error::msg)?;
+    let log_number = |name: &str, default: u64| match env::var(name) {
+        Ok(value) => value
+            .parse::<u64>()
+            .with_context(|| format!("Invalid {name}: {value}, expected a number")),
+        Err(_) => Ok(default),
+    };
+    let max_size_mb = log_number("LOG_MAX_SIZE_MB", 100)?;
+    let max_files = log_number("LOG_MAX_FILES", 7)?;
+    let log_file = env::var_os("LOG_FILE")
+        .map(|path| {
+            let max_size = max_size_mb.saturating_mul(1024 * 1024);
+            logging::RollingFile::open(path.into(), max_size, max_files as usize)
+        })
+        .transpose()
+        .context("failed to open LOG_FILE")?;
+    // Lines are written to the file on a background thread, the guard flushes them on exit.
+    let (log_writer, _log_guard) = match log_file {
+        Some(file) => {
+            let (file, guard) = tracing_appender::non_blocking::NonBlockingBuilder::default()
+                .lossy(false)
+                .finish(file);
+            (BoxMakeWriter::new(std::io::stdout.and(file)), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| format!("{}=debug", env!("CARGO_CRATE_NAME")).into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(logging::layer(log_format, log_writer, _log_guard.is_none()))
+        .with(telemetry.as_ref().map(|(_, tracer)| {
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer.clone())
+                .with_filter(filter_fn(|meta| meta.target() != logging::CONTEXT_TARGET))
+        }))
         .init();
+    if args.first().is_some_and(|cmd| cmd == "agent") {
+        let dirs = if work_dirs.is_empty() {
+            vec![work_dir]
+        } else {
+            work_dirs
+        };
+        return agent::run_agent(dirs, output_dir).await;
+    }
+    // The secret also encrypts stored task secrets, so it is needed without authentication too
+    let app_secret = load_or_create_secret().context("failed to load APP_SECRET")?;
+    let secret = if insecure_no_auth {
+        None
+    } else {
+        Some(app_secret.clone())
+    };
+    let tls = tls::TlsSettings::from_env()?;
+    security_self_check(&host, secret.as_deref(), tls.is_some());
     info!("Listening on {}", &server_url);
     info!("Work directory: {}", work_dir.display());
 
@@ -70,6 +146,41 @@ async fn main() -> anyhow::Result<()> {
     task::create_table_if_not_exists(&conn)
         .await
         .expect("Failed to create table");
+    secret::create_table_if_not_exists(&conn)
+        .await
+        .expect("Failed to create table");
+
+    let shutdown_policy = env::var("SHUTDOWN_POLICY")
+        .unwrap_or("interrupt".to_string())
+        .parse::<ShutdownPolicy>()
+        .map_err(anyhow::Error::msg)?;
+    // Local tasks still marked running were cut short when the previous process exited
+    let reset = task::reset_running_tasks(&conn, shutdown_policy.interrupted_status())
+        .await
+        .context("failed to reset running tasks")?;
+    if reset > 0 {
+        warn!("{} tasks were left running by the previous process", reset);
+    }
+
+    let mut dir_settings = std::collections::HashMap::new();
+    for dir in work_dirs.iter().chain([&work_dir]) {
+        let settings = settings::DirSettings::load(dir)?;
+        dir_settings.insert(dir.clone(), settings);
+    }
+
+    let executor_errors = executor::check_executors(&dir_settings);
+
+    let mut finished = None;
+    if let Ok(path) = env::var("WEBHOOKS_FILE") {
+        let hooks = webhooks::load(std::path::Path::new(&path))
+            .context("failed to load WEBHOOKS_FILE")?;
+        info!("Loaded {} webhooks", hooks.len());
+        let public_url = env::var("PUBLIC_URL").unwrap_or_default();
+        let webhooks = webhooks::Webhooks::new(hooks, &public_url, output_dir.clone())?;
+        let (finished_tx, finished_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(webhooks::dispatch(Arc::new(webhooks), finished_rx));
+        finished = Some(finished_tx);
+    }
 
     let (sender, _) = broadcast::channel(10);
     let (shutdown_tx, _) = broadcast::channel(10);
@@ -78,11 +189,41 @@ async fn main() -> anyhow::Result<()> {
         work_dir: Arc::new(RwLock::new(work_dir.clone())),
         work_dirs,
         logs_dir: logs_dir.clone(),
-        sender: sender.clone(),
-        shutdown_tx: shutdown_tx.clone(),
+        sender,
+        shutdown_tx,
+        limiter: Arc::new(limits::RateLimiter::new(limits::Limits::from_env())),
+        settings: Arc::new(dir_settings),
+        submit_lock: Arc::new(tokio::sync::Mutex::new(())),
+        max_parallel: env::var("MAX_PARALLEL")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&max| max > 0)
+            .unwrap_or(1),
+        runner: Arc::new(RunnerSignal::default()),
+        shutdown_policy,
+        shutdown_timeout: Duration::from_secs(
+            env::var("SHUTDOWN_TIMEOUT")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(30),
+        ),
+        executor_errors: Arc::new(RwLock::new(executor_errors)),
+        cipher: Arc::new(secret::SecretCipher::new(&app_secret)),
+        output_dir: output_dir.clone(),
+        workers: Arc::new(workers::Workers::default()),
+        labels: workers::parse_labels(&env::var("LABELS").unwrap_or_default()),
+        metrics: Arc::new(metrics::Metrics::default()),
+        min_free_disk_mb: env::var("MIN_FREE_DISK_MB")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(100),
+        dedup_policy: env::var("DEDUP_POLICY")
+            .map_or(Ok(settings::DedupPolicy::default()), |value| value.parse())
+            .map_err(anyhow::Error::msg)?,
+        finished,
     };
 
-    let runner = start_runner(state.clone(), output_dir.clone());
+    let runner = start_runner(state.clone());
 
     // build our application with some routes
     let mut router = Router::new()
@@ -92,13 +233,43 @@ async fn main() -> anyhow::Result<()> {
         .route("/run", post(add_task))
         .route("/cancel/{id}", post(cancel_task))
         .route("/reset/{id}", post(reset_task))
+        .route("/list", get(list_task_after))
         .route("/list/{page}", get(list_task))
+        .route("/task/{id}", get(get_task))
+        .route("/stats", get(get_stats))
+        .route("/metrics", get(get_metrics))
+        .route("/search", get(search_logs))
+        .route("/quota", get(get_quota))
+        .route("/secrets", get(list_secrets))
+        .route("/secret/{name}", put(save_secret).delete(delete_secret))
         .route("/status", get(task_status_sse))
-        .with_state(state);
-    if !secret.is_empty() {
-        router = router.route_layer(middleware::from_fn_with_state(secret, validate_jwt))
+        .route("/workers", get(list_workers))
+        .with_state(state.clone());
+    let mut agent_router = Router::new()
+        .route("/agent/register", post(register_worker))
+        .route("/agent/poll", get(poll_task))
+        .route("/agent/task/{id}/log", post(upload_log))
+        .route("/agent/task/{id}/artifact", put(upload_artifact))
+        .route("/agent/task/{id}/status", post(report_status))
+        .with_state(state.clone());
+    if let Some(secret) = &secret {
+        router = router.route_layer(middleware::from_fn_with_state(
+            secret.clone(),
+            validate_jwt,
+        ));
+        agent_router = agent_router
+            .route_layer(middleware::from_fn_with_state(secret.clone(), validate_agent));
     }
+    router = router.merge(agent_router);
+    // Probes of load balancers and watchdogs need no token, only users see the details.
     router = router
+        .route("/healthz", get(healthz))
+        .route(
+            "/readyz",
+            get(readyz)
+                .with_state(state.clone())
+                .layer(middleware::from_fn_with_state(secret.clone(), identify_user)),
+        )
         .nest_service(
             "/logs",
             ServiceBuilder::new()
@@ -109,15 +280,69 @@ async fn main() -> anyhow::Result<()> {
                 .service(ServeDir::new(logs_dir)),
         )
         .nest_service("/package", ServeDir::new(output_dir))
-        .fallback_service(ServeDir::new("public").precompressed_br());
+        .fallback_service(ServeDir::new("public").precompressed_br())
+        .layer(middleware::from_fn(telemetry::track_request))
+        .layer(middleware::from_fn_with_state(
+            state.metrics.clone(),
+            metrics::track_http,
+        ));
 
     // run it
-    let listener = tokio::net::TcpListener::bind(server_url)
-        .await
-        .context("failed to bind TCP listener")?;
-    axum::serve(listener, router)
-        .with_graceful_shutdown(shutdown_signal(sender, shutdown_tx, runner))
-        .await
-        .context("axum::serve failed")?;
+    let shutdown = shutdown_signal(state.clone(), runner);
+    match tls {
+        Some(tls) => {
+            let config = RustlsConfig::from_config(Arc::new(tls.load()?));
+            tls::watch(tls, config.clone());
+            if let Ok(redirect_port) = env::var("HTTP_REDIRECT_PORT") {
+                let listener = tokio::net::TcpListener::bind(format!("{host}:{redirect_port}"))
+                    .await
+                    .context("failed to bind HTTP redirect listener")?;
+                info!("Redirecting HTTP on port {} to HTTPS", redirect_port);
+                tokio::spawn(async move {
+                    let router = tls::redirect_router(port);
+                    if let Err(err) = axum::serve(listener, router).await {
+                        error!("HTTP redirect listener failed: {}", err);
+                    }
+                });
+            }
+            let addr = tokio::net::lookup_host(&server_url)
+                .await?
+                .next()
+                .context("failed to resolve listen address")?;
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown.await;
+                    handle.graceful_shutdown(Some(Duration::from_secs(5)));
+                }
+            });
+            axum_server::bind(addr)
+                .acceptor(tls::ClientCertAcceptor::new(config))
+                .handle(handle)
+                .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .context("HTTPS server failed")?;
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(server_url)
+                .await
+                .context("failed to bind TCP listener")?;
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown)
+            .await
+            .context("axum::serve failed")?;
+        }
+    }
+    if let Some((provider, _)) = telemetry {
+        // The exporter blocks while it sends the last spans.
+        let result = tokio::task::spawn_blocking(move || provider.shutdown()).await?;
+        if let Err(err) = result {
+            warn!("Failed to flush traces: {}", err);
+        }
+    }
     Ok(())
 }
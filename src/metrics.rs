@@ -0,0 +1,217 @@
+use crate::task::{self, TaskStatus};
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bucket bounds of task durations, in seconds.
+const TASK_BUCKETS: [f64; 11] = [
+    1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0,
+];
+/// Bucket bounds of HTTP request durations, in seconds.
+const HTTP_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(&mut self.counts) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    started: BTreeMap<String, u64>,
+    finished: BTreeMap<(String, String), u64>,
+    durations: BTreeMap<String, Histogram>,
+    http_requests: BTreeMap<(String, String, u16), u64>,
+    http_durations: BTreeMap<(String, String), Histogram>,
+    /// Tasks started by this process which have not finished yet.
+    running: HashSet<i32>,
+    busy_since: Option<Instant>,
+    busy: Duration,
+}
+
+/// Counters of tasks and HTTP requests exposed at `/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+fn status_name(status: TaskStatus) -> String {
+    format!("{:?}", status).to_lowercase()
+}
+
+/// Escapes a label value of the text exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn task_started(&self, task: &task::Model) {
+        let mut inner = self.inner.lock().unwrap();
+        let recipe = task::recipe_of(&task.command).to_string();
+        *inner.started.entry(recipe).or_default() += 1;
+        if inner.running.insert(task.id) && inner.running.len() == 1 {
+            inner.busy_since = Some(Instant::now());
+        }
+    }
+
+    pub fn task_finished(&self, task: &task::Model) {
+        let mut inner = self.inner.lock().unwrap();
+        // Only tasks started by this process were counted as started.
+        if !inner.running.remove(&task.id) {
+            return;
+        }
+        if inner.running.is_empty()
+            && let Some(since) = inner.busy_since.take()
+        {
+            inner.busy += since.elapsed();
+        }
+        let recipe = task::recipe_of(&task.command).to_string();
+        let key = (recipe.clone(), status_name(task.status));
+        *inner.finished.entry(key).or_default() += 1;
+        if let Some(duration) = task.duration() {
+            inner
+                .durations
+                .entry(recipe)
+                .or_insert_with(|| Histogram::new(&TASK_BUCKETS))
+                .observe(duration.as_seconds_f64());
+        }
+    }
+
+    fn http_request(&self, method: String, route: String, status: u16, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .http_requests
+            .entry((method.clone(), route.clone(), status))
+            .or_default() += 1;
+        inner
+            .http_durations
+            .entry((method, route))
+            .or_insert_with(|| Histogram::new(&HTTP_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Renders the Prometheus text format, with the task counts by status and the number of
+    /// status subscribers taken at scrape time.
+    pub fn render(&self, queue: &[(TaskStatus, u64)], subscribers: usize) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP remote_task_tasks Tasks in the database by status.\n");
+        out.push_str("# TYPE remote_task_tasks gauge\n");
+        for (status, count) in queue {
+            let status = status_name(*status);
+            let _ = writeln!(out, "remote_task_tasks{{status=\"{status}\"}} {count}");
+        }
+
+        out.push_str("# HELP remote_task_tasks_started_total Tasks started by recipe.\n");
+        out.push_str("# TYPE remote_task_tasks_started_total counter\n");
+        for (recipe, count) in &inner.started {
+            let recipe = escape(recipe);
+            let _ = writeln!(
+                out,
+                "remote_task_tasks_started_total{{recipe=\"{recipe}\"}} {count}"
+            );
+        }
+
+        out.push_str("# HELP remote_task_tasks_finished_total Tasks finished by recipe and outcome.\n");
+        out.push_str("# TYPE remote_task_tasks_finished_total counter\n");
+        for ((recipe, status), count) in &inner.finished {
+            let recipe = escape(recipe);
+            let _ = writeln!(
+                out,
+                "remote_task_tasks_finished_total{{recipe=\"{recipe}\",status=\"{status}\"}} {count}"
+            );
+        }
+
+        out.push_str("# HELP remote_task_task_duration_seconds Run time of finished tasks.\n");
+        out.push_str("# TYPE remote_task_task_duration_seconds histogram\n");
+        for (recipe, histogram) in &inner.durations {
+            let labels = format!("recipe=\"{}\"", escape(recipe));
+            histogram.render(&mut out, "remote_task_task_duration_seconds", &labels);
+        }
+
+        let busy = inner.busy + inner.busy_since.map_or(Duration::ZERO, |since| since.elapsed());
+        out.push_str("# HELP remote_task_runner_busy_seconds_total Time with at least one task running.\n");
+        out.push_str("# TYPE remote_task_runner_busy_seconds_total counter\n");
+        let _ = writeln!(out, "remote_task_runner_busy_seconds_total {}", busy.as_secs_f64());
+
+        out.push_str("# HELP remote_task_status_subscribers Clients listening to /status.\n");
+        out.push_str("# TYPE remote_task_status_subscribers gauge\n");
+        let _ = writeln!(out, "remote_task_status_subscribers {subscribers}");
+
+        out.push_str("# HELP remote_task_http_requests_total HTTP requests by route and status.\n");
+        out.push_str("# TYPE remote_task_http_requests_total counter\n");
+        for ((method, route, status), count) in &inner.http_requests {
+            let route = escape(route);
+            let _ = writeln!(
+                out,
+                "remote_task_http_requests_total{{method=\"{method}\",route=\"{route}\",status=\"{status}\"}} {count}"
+            );
+        }
+
+        out.push_str("# HELP remote_task_http_request_duration_seconds Time to answer HTTP requests.\n");
+        out.push_str("# TYPE remote_task_http_request_duration_seconds histogram\n");
+        for ((method, route), histogram) in &inner.http_durations {
+            let labels = format!("method=\"{method}\",route=\"{}\"", escape(route));
+            histogram.render(&mut out, "remote_task_http_request_duration_seconds", &labels);
+        }
+        out
+    }
+}
+
+/// Middleware counting requests by route pattern, so ids in paths do not add labels.
+/// Long lived responses like `/status` are counted when their headers are sent.
+pub async fn track_http(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("other".to_string(), |path| path.as_str().to_string());
+    let start = Instant::now();
+    let response = next.run(request).await;
+    metrics.http_request(method, route, response.status().as_u16(), start.elapsed());
+    response
+}
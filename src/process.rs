@@ -0,0 +1,190 @@
+use crate::executor::Invocation;
+use serde::Deserialize;
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// Limits and environment of the task processes of a work directory.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ProcessSettings {
+    /// CPU time in seconds (`RLIMIT_CPU`).
+    pub cpu_seconds: Option<u64>,
+    /// Address space in megabytes (`RLIMIT_AS`).
+    pub memory_mb: Option<u64>,
+    /// Open file descriptors (`RLIMIT_NOFILE`).
+    pub open_files: Option<u64>,
+    /// Scheduling priority, from -20 (highest) to 19 (lowest).
+    pub nice: Option<i32>,
+    /// Best effort I/O priority, from 0 (highest) to 7 (lowest), Linux only.
+    pub ionice: Option<u8>,
+    /// Server environment variables passed to tasks, glob patterns allowed.
+    /// All of them are passed when missing.
+    pub env_allow: Option<Vec<String>>,
+    /// User id to run tasks as, the server must run as root to switch to it.
+    pub uid: Option<u32>,
+    /// Group id to run tasks as.
+    pub gid: Option<u32>,
+    /// Runs tasks in a bubblewrap sandbox when present.
+    pub sandbox: Option<SandboxSettings>,
+}
+
+/// Bubblewrap sandbox where everything but the work and output directories is read only.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SandboxSettings {
+    /// Path of the `bwrap` binary, found on `PATH` by default.
+    pub binary: Option<PathBuf>,
+    /// Other writable directories.
+    pub writable: Vec<PathBuf>,
+    /// Extra `bwrap` flags, like `["--unshare-net"]`.
+    pub args: Vec<String>,
+}
+
+impl SandboxSettings {
+    /// Wraps the invocation of a task into `bwrap`.
+    pub fn wrap(&self, invocation: Invocation, work_dir: &Path, output_dir: &Path) -> Invocation {
+        let mut args = vec![
+            "--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp",
+            "--unshare-pid", "--die-with-parent",
+        ]
+        .into_iter()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+        for dir in [work_dir, output_dir]
+            .into_iter()
+            .chain(self.writable.iter().map(PathBuf::as_path))
+        {
+            let dir = dir.to_string_lossy().to_string();
+            args.extend(["--bind".to_string(), dir.clone(), dir]);
+        }
+        args.extend(self.args.iter().cloned());
+        args.extend([
+            "--chdir".to_string(),
+            work_dir.to_string_lossy().to_string(),
+            "--".to_string(),
+            invocation.program.to_string_lossy().to_string(),
+        ]);
+        args.extend(invocation.args);
+        Invocation {
+            program: self.binary.clone().unwrap_or(PathBuf::from("bwrap")),
+            args,
+            ..invocation
+        }
+    }
+}
+
+impl ProcessSettings {
+    /// Fails on limits and users the platform cannot apply, rather than running tasks without them.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let limits = [
+            ("cpu_seconds", self.cpu_seconds.is_some()),
+            ("memory_mb", self.memory_mb.is_some()),
+            ("open_files", self.open_files.is_some()),
+            ("nice", self.nice.is_some()),
+            ("uid", self.uid.is_some()),
+            ("gid", self.gid.is_some()),
+        ];
+        if !cfg!(unix)
+            && let Some((name, _)) = limits.iter().find(|(_, set)| *set)
+        {
+            anyhow::bail!("process.{name} is not supported on this platform");
+        }
+        if !cfg!(target_os = "linux") && self.ionice.is_some() {
+            anyhow::bail!("process.ionice is only supported on Linux");
+        }
+        Ok(())
+    }
+
+    /// Applies the settings to a task command before it is spawned.
+    pub fn apply(&self, command: &mut tokio::process::Command) {
+        if let Some(allow) = &self.env_allow {
+            command.env_clear();
+            for (key, value) in env::vars_os() {
+                let name = key.to_string_lossy();
+                if allow
+                    .iter()
+                    .any(|pattern| wildmatch::WildMatch::new(pattern).matches(&name))
+                {
+                    command.env(&key, value);
+                }
+            }
+        }
+        #[cfg(unix)]
+        unix::apply(self, command);
+    }
+}
+
+/// Kills the process group of a task, so processes it started do not outlive it.
+pub fn kill_group(pid: Option<u32>) {
+    #[cfg(unix)]
+    if let Some(pid) = pid {
+        // The group may already be gone, which is fine.
+        unsafe {
+            libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = pid;
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::ProcessSettings;
+    use std::io;
+
+    pub fn apply(settings: &ProcessSettings, command: &mut tokio::process::Command) {
+        // Each task leads its own process group, killed as a whole by `kill_group`.
+        command.process_group(0);
+        if let Some(gid) = settings.gid {
+            command.gid(gid);
+        }
+        if let Some(uid) = settings.uid {
+            command.uid(uid);
+        }
+        let settings = settings.clone();
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(seconds) = settings.cpu_seconds {
+                    set_limit(libc::RLIMIT_CPU, seconds)?;
+                }
+                if let Some(megabytes) = settings.memory_mb {
+                    set_limit(libc::RLIMIT_AS, megabytes.saturating_mul(1024 * 1024))?;
+                }
+                if let Some(count) = settings.open_files {
+                    set_limit(libc::RLIMIT_NOFILE, count)?;
+                }
+                if let Some(nice) = settings.nice
+                    && libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                #[cfg(target_os = "linux")]
+                if let Some(level) = settings.ionice {
+                    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+                    const IOPRIO_CLASS_BE: libc::c_int = 2;
+                    let priority = (IOPRIO_CLASS_BE << 13) | libc::c_int::from(level.min(7));
+                    if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    type Resource = libc::__rlimit_resource_t;
+    #[cfg(not(target_os = "linux"))]
+    type Resource = libc::c_int;
+
+    fn set_limit(resource: Resource, value: u64) -> io::Result<()> {
+        let limit = libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        };
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
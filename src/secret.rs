@@ -0,0 +1,146 @@
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use sea_orm::{DbConn, QueryOrder, Set, entity::prelude::*, sea_query::OnConflict};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Named secret, injected as environment variable into the recipes allowed to use it.
+#[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "secret")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    #[sea_orm(unique)]
+    pub name: String,
+    /// Base64 of the nonce followed by the encrypted value.
+    #[serde(skip)]
+    pub value: String,
+    /// Recipe names or glob patterns which receive the secret.
+    pub recipes: Json,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: time::OffsetDateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    pub fn recipes(&self) -> Vec<String> {
+        serde_json::from_value(self.recipes.clone()).unwrap_or_default()
+    }
+
+    pub fn allows(&self, recipe: &str) -> bool {
+        self.recipes()
+            .iter()
+            .any(|pattern| wildmatch::WildMatch::new(pattern).matches(recipe))
+    }
+}
+
+/// Encrypts secret values with a key derived from `APP_SECRET`.
+pub struct SecretCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SecretCipher {
+    pub fn new(app_secret: &str) -> Self {
+        let key = Sha256::new()
+            .chain_update(b"remote-task secrets:")
+            .chain_update(app_secret.as_bytes())
+            .finalize();
+        SecretCipher {
+            cipher: ChaCha20Poly1305::new(&key),
+        }
+    }
+
+    pub fn encrypt(&self, value: &str) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut data = nonce.to_vec();
+        data.extend(
+            self.cipher
+                .encrypt(&nonce, value.as_bytes())
+                .expect("Encrypt secret"),
+        );
+        BASE64.encode(data)
+    }
+
+    pub fn decrypt(&self, value: &str) -> Result<String, DbErr> {
+        let error = || DbErr::Custom("Cannot decrypt secret, was APP_SECRET changed?".to_owned());
+        let data = BASE64.decode(value).map_err(|_| error())?;
+        if data.len() < 12 {
+            return Err(error());
+        }
+        let (nonce, ciphertext) = data.split_at(12);
+        let plain = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| error())?;
+        String::from_utf8(plain).map_err(|_| error())
+    }
+}
+
+pub async fn create_table_if_not_exists(db: &DbConn) -> Result<(), DbErr> {
+    let backend = db.get_database_backend();
+    let schema = sea_orm::Schema::new(backend);
+    let mut statement = schema.create_table_from_entity(Entity);
+    let statement = backend.build(statement.if_not_exists());
+    db.execute(statement).await?;
+    Ok(())
+}
+
+pub async fn list_secrets(db: &DbConn) -> Result<Vec<Model>, DbErr> {
+    Entity::find().order_by_asc(Column::Name).all(db).await
+}
+
+pub async fn save_secret(
+    db: &DbConn,
+    cipher: &SecretCipher,
+    name: String,
+    value: &str,
+    recipes: Vec<String>,
+) -> Result<(), DbErr> {
+    let secret = ActiveModel {
+        name: Set(name),
+        value: Set(cipher.encrypt(value)),
+        recipes: Set(serde_json::json!(recipes)),
+        updated_at: Set(TimeDateTimeWithTimeZone::now_utc()),
+        ..Default::default()
+    };
+    Entity::insert(secret)
+        .on_conflict(
+            OnConflict::column(Column::Name)
+                .update_columns([Column::Value, Column::Recipes, Column::UpdatedAt])
+                .to_owned(),
+        )
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_secret(db: &DbConn, name: &str) -> Result<bool, DbErr> {
+    Entity::delete_many()
+        .filter(Column::Name.eq(name))
+        .exec(db)
+        .await
+        .map(|res| res.rows_affected == 1)
+}
+
+/// Decrypted secrets which the recipe may use, as name and value pairs.
+pub async fn secrets_for_recipe(
+    db: &DbConn,
+    cipher: &SecretCipher,
+    recipe: &str,
+) -> Result<Vec<(String, String)>, DbErr> {
+    let mut secrets = Vec::new();
+    for secret in list_secrets(db).await? {
+        if secret.allows(recipe) {
+            let value = cipher.decrypt(&secret.value)?;
+            secrets.push((secret.name, value));
+        }
+    }
+    Ok(secrets)
+}
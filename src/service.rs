@@ -1,27 +1,44 @@
+use crate::executor::{Invocation, executor_for};
+use crate::container;
+use crate::health;
+use crate::limits::{RateLimiter, Usage};
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::process::{self, ProcessSettings};
+use crate::secret::{self, SecretCipher};
+use crate::settings::{DedupPolicy, DirSettings};
 use crate::task;
+use crate::telemetry;
+use crate::stats;
+use crate::tls::ClientIdentity;
+use crate::workers::{self, AgentJob, Registration, StatusReport, WorkerQuery, WorkerStatus, Workers};
+use anyhow::Context;
 use axum::{
-    Json,
-    extract::{Path, Request, State, Query},
-    http::StatusCode,
+    Extension, Json,
+    extract::{ConnectInfo, Path, Request, State, Query},
+    http::{HeaderMap, StatusCode, header},
     middleware::Next,
     response::{
-        IntoResponse, Redirect, sse::{Event, Sse}
+        IntoResponse, Redirect, Response, sse::{Event, Sse}
     },
 };
+use axum::body::{Body, Bytes};
 use axum_extra::extract::CookieJar;
 use sea_orm::DatabaseConnection;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
 use std::io::Write;
-use std::process::{Command, Stdio};
+use std::net::SocketAddr;
+use std::process::Stdio;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::path::PathBuf;
+use std::path::{Component, PathBuf};
 use std::sync::{Arc, RwLock};
-use tokio::sync::broadcast;
-use tokio::task::JoinHandle;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::sync::{Notify, broadcast, watch};
+use tokio::task::{JoinHandle, JoinSet};
 use tokio_stream::StreamExt as TokioStreamExt;
-use tracing::{error, info};
+use tracing::{Instrument, error, info, warn};
 use tokio_stream::wrappers::{BroadcastStream, errors::BroadcastStreamRecvError};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -34,8 +51,98 @@ pub struct TaskStatusEvent {
 #[derive(Clone, Debug)]
 pub struct ShutdownSignal;
 
-static RUNNING: AtomicBool = AtomicBool::new(true);
-static CHECKING: AtomicBool = AtomicBool::new(true);
+/// How long to keep copying task output after the task process exits.
+const OUTPUT_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Bytes of a log which are indexed for search, taken from its start and its end.
+const MAX_INDEXED_LOG: u64 = 4 * 1024 * 1024;
+
+/// Suggested wait before resubmitting when a pending task quota is exhausted.
+const QUOTA_RETRY_AFTER: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Wakes the task runner when tasks are submitted, and tells it to stop.
+#[derive(Debug)]
+pub struct RunnerSignal {
+    wakeup: Notify,
+    stopping: AtomicBool,
+    terminate: watch::Sender<bool>,
+    /// When the runner loop last looked for tasks.
+    last_tick: std::sync::Mutex<std::time::Instant>,
+}
+
+impl Default for RunnerSignal {
+    fn default() -> Self {
+        RunnerSignal {
+            wakeup: Notify::new(),
+            stopping: AtomicBool::new(false),
+            terminate: watch::Sender::new(false),
+            last_tick: std::sync::Mutex::new(std::time::Instant::now()),
+        }
+    }
+}
+
+impl RunnerSignal {
+    fn tick(&self) {
+        *self.last_tick.lock().unwrap() = std::time::Instant::now();
+    }
+
+    /// Time since the runner loop last looked for tasks.
+    pub fn since_tick(&self) -> std::time::Duration {
+        self.last_tick.lock().unwrap().elapsed()
+    }
+
+    pub fn wake(&self) {
+        self.wakeup.notify_one();
+    }
+
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.wakeup.notify_one();
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Kills the child processes of all running tasks.
+    pub fn terminate(&self) {
+        self.terminate.send_replace(true);
+    }
+}
+
+/// What happens to running tasks when the server shuts down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownPolicy {
+    /// Wait until all running tasks finish.
+    Wait,
+    /// Kill running tasks after the deadline and mark them interrupted.
+    Interrupt,
+    /// Kill running tasks after the deadline and queue them again.
+    Requeue,
+}
+
+impl std::str::FromStr for ShutdownPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wait" => Ok(ShutdownPolicy::Wait),
+            "interrupt" => Ok(ShutdownPolicy::Interrupt),
+            "requeue" => Ok(ShutdownPolicy::Requeue),
+            _ => Err(format!("unknown shutdown policy: {s}")),
+        }
+    }
+}
+
+impl ShutdownPolicy {
+    /// Status of a task whose run was cut short by a shutdown.
+    pub fn interrupted_status(self) -> task::TaskStatus {
+        match self {
+            ShutdownPolicy::Requeue => task::TaskStatus::Pending,
+            _ => task::TaskStatus::Interrupted,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct AppState {
@@ -45,6 +152,71 @@ pub struct AppState {
     pub logs_dir: PathBuf,
     pub sender: broadcast::Sender<TaskStatusEvent>,
     pub shutdown_tx: broadcast::Sender<ShutdownSignal>,
+    pub limiter: Arc<RateLimiter>,
+    pub settings: Arc<HashMap<PathBuf, DirSettings>>,
+    /// Serializes submissions so duplicate checks and inserts do not interleave.
+    pub submit_lock: Arc<tokio::sync::Mutex<()>>,
+    /// Maximum number of tasks running at the same time.
+    pub max_parallel: usize,
+    pub runner: Arc<RunnerSignal>,
+    pub shutdown_policy: ShutdownPolicy,
+    /// How long to wait for running tasks before applying the shutdown policy.
+    pub shutdown_timeout: std::time::Duration,
+    /// Why the executor of a work directory cannot run tasks, by directory.
+    pub executor_errors: Arc<RwLock<HashMap<PathBuf, String>>>,
+    pub cipher: Arc<SecretCipher>,
+    pub output_dir: PathBuf,
+    pub workers: Arc<Workers>,
+    /// `LABELS`: labels of the server machine, matched against task requirements.
+    pub labels: Vec<String>,
+    pub metrics: Arc<Metrics>,
+    /// `MIN_FREE_DISK_MB`: free space in the logs directory below which the server is not ready.
+    pub min_free_disk_mb: u64,
+    /// `DEDUP_POLICY`: handling of identical tasks when neither the request nor the recipe sets it.
+    pub dedup_policy: DedupPolicy,
+    /// Tasks which reached a final status, for the webhooks when there are any.
+    pub finished: Option<tokio::sync::mpsc::UnboundedSender<task::Model>>,
+}
+
+impl AppState {
+    pub fn dir_settings(&self, dir: &std::path::Path) -> DirSettings {
+        self.settings.get(dir).cloned().unwrap_or_default()
+    }
+
+    /// State with the default settings and a new database in `dir`, which is also the
+    /// work, output and logs directory.
+    #[cfg(test)]
+    pub async fn for_test(dir: &std::path::Path) -> Self {
+        let _ = std::fs::remove_file(dir.join("tasks.db"));
+        let url = format!("sqlite:{}?mode=rwc", dir.join("tasks.db").display());
+        let conn = sea_orm::Database::connect(url).await.unwrap();
+        task::create_table_if_not_exists(&conn).await.unwrap();
+        secret::create_table_if_not_exists(&conn).await.unwrap();
+        AppState {
+            conn,
+            work_dir: Arc::new(RwLock::new(dir.to_path_buf())),
+            work_dirs: vec![dir.to_path_buf()],
+            logs_dir: dir.join("logs"),
+            sender: broadcast::channel(10).0,
+            shutdown_tx: broadcast::channel(10).0,
+            limiter: Arc::new(RateLimiter::new(Default::default())),
+            settings: Arc::new(HashMap::from([(dir.to_path_buf(), DirSettings::default())])),
+            submit_lock: Arc::default(),
+            max_parallel: 1,
+            runner: Arc::default(),
+            shutdown_policy: ShutdownPolicy::Interrupt,
+            shutdown_timeout: std::time::Duration::from_secs(1),
+            executor_errors: Arc::default(),
+            cipher: Arc::new(SecretCipher::new("test secret")),
+            output_dir: dir.to_path_buf(),
+            workers: Arc::default(),
+            labels: Vec::new(),
+            metrics: Arc::default(),
+            min_free_disk_mb: 0,
+            dedup_policy: DedupPolicy::default(),
+            finished: None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -53,46 +225,114 @@ pub struct DirInfo {
     pub all_dirs: Vec<String>,
 }
 
-pub fn start_runner(state: AppState, output_dir: std::path::PathBuf) -> JoinHandle<()> {
+pub fn start_runner(state: AppState) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_millis(500));
+        let mut running = JoinSet::new();
+        // Concurrency keys of the running tasks
+        let mut active = HashMap::new();
         loop {
-            if !RUNNING.load(Ordering::SeqCst) {
+            if state.runner.is_stopping() {
                 break;
             }
-            if CHECKING.load(Ordering::SeqCst) {
-                if let Err(err) = run_tasks(&state, &output_dir).await {
-                    error!("Failed to run tasks: {}", err);
+            state.runner.tick();
+            if let Err(err) = run_tasks(&state, &mut running, &mut active).await {
+                error!("Failed to run tasks: {}", err);
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                continue;
+            }
+            // Sleep until a task is submitted or a running task finishes,
+            // or until a worker would be lost if it stopped polling.
+            let expiry = state.workers.next_expiry();
+            let lost_worker = async {
+                if let Some(expiry) = expiry {
+                    tokio::time::sleep_until(expiry.into()).await;
                 }
+            };
+            tokio::select! {
+                _ = state.runner.wakeup.notified() => {}
+                Some(result) = running.join_next() => finish_task(result, &mut active),
+                _ = lost_worker, if expiry.is_some() => {}
             }
-            interval.tick().await;
+        }
+        while let Some(result) = running.join_next().await {
+            finish_task(result, &mut active);
         }
     })
 }
 
-pub async fn shutdown_signal(
-    sender: broadcast::Sender<TaskStatusEvent>,
-    shutdown_tx: broadcast::Sender<ShutdownSignal>,
-    runner: JoinHandle<()>,
-) {
-    // Wait for Ctrl+C signal
-    tokio::signal::ctrl_c().await.expect("Listen for Ctrl+C");
+fn finish_task(result: Result<i32, tokio::task::JoinError>, active: &mut HashMap<i32, String>) {
+    match result {
+        Ok(id) => {
+            active.remove(&id);
+        }
+        Err(err) => error!("Task join error: {}", err),
+    }
+}
+
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+pub async fn shutdown_signal(state: AppState, mut runner: JoinHandle<()>) {
+    // Wait for Ctrl+C or SIGTERM
+    wait_for_signal().await;
 
     info!("Shutdown server...");
-    RUNNING.store(false, Ordering::SeqCst);
+    state.runner.stop();
 
-    // Wait for runner to finish
-    match runner.await {
+    // Wait for runner to finish, a second signal or the deadline cuts running tasks short.
+    // With the wait policy there is no deadline, but a second signal still stops stuck tasks.
+    let deadline = async {
+        if state.shutdown_policy == ShutdownPolicy::Wait {
+            std::future::pending::<()>().await
+        } else {
+            tokio::time::sleep(state.shutdown_timeout).await
+        }
+    };
+    let finished = tokio::select! {
+        result = &mut runner => Some(result),
+        _ = deadline => {
+            info!("Shutdown deadline reached, terminating running tasks");
+            None
+        }
+        _ = wait_for_signal() => {
+            info!("Signal received again, terminating running tasks");
+            None
+        }
+    };
+    let result = match finished {
+        Some(result) => result,
+        None => {
+            state.runner.terminate();
+            runner.await
+        }
+    };
+    match result {
         Ok(_) => info!("Runner finished"),
         Err(err) => error!("Runner join error: {}", err),
     }
 
     // Send shutdown signal to all SSE clients
-    let _ = shutdown_tx.send(ShutdownSignal);
+    let _ = state.shutdown_tx.send(ShutdownSignal);
     info!("Shutdown signal sent to SSE clients");
 
     // Drop sender to close task status channel
-    drop(sender);
+    drop(state);
 
     // Give SSE clients a moment to close
     tokio::time::sleep(std::time::Duration::from_millis(100)).await;
@@ -100,75 +340,554 @@ pub async fn shutdown_signal(
     info!("SSE connections closed");
 }
 
+/// Starts pending tasks whose concurrency key is free, locally or on a worker serving
+/// their directory, and records why the others wait.
 pub async fn run_tasks(
     state: &AppState,
-    output_dir: &std::path::Path,
+    running: &mut JoinSet<i32>,
+    active: &mut HashMap<i32, String>,
 ) -> Result<(), sea_orm::DbErr> {
-    let tasks = task::pending_tasks(&state.conn).await?;
-    if tasks.is_empty() {
-        CHECKING.store(false, Ordering::SeqCst);
+    for id in state.workers.expire() {
+        warn!(task_id = id, "Task {} was lost with its worker", id);
+        update_task(state, id, task::TaskStatus::Interrupted).await?;
     }
+    let mut remote = state.workers.running();
+    let tasks = task::pending_tasks(&state.conn).await?;
     for task in tasks {
-        info!("Running task: {}", task.id);
-        update_task(state, task.id, task::TaskStatus::Running).await?;
-        let log_dir = state.logs_dir.join(task.month());
-        if !log_dir.is_dir() {
-            std::fs::create_dir_all(&log_dir)
-                .unwrap_or_else(|err| error!("Failed to create log directory: {}", err));
-        }
-        let log_file = log_dir.join(format!("{}.log", task.id));
-        let output_file = task.output.map(|path| output_dir.join(path));
-        let work_dir = state.work_dir.read().unwrap().clone();
-        match run_just_task(
-            &task.command,
-            &work_dir,
-            &log_file,
-            output_file.as_ref(),
-        )
-        .await
+        let span = logging::task_span(&task);
+        schedule_task(state, running, active, &mut remote, task)
+            .instrument(span)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Starts a pending task here or on a worker, or records why it waits.
+async fn schedule_task(
+    state: &AppState,
+    running: &mut JoinSet<i32>,
+    active: &mut HashMap<i32, String>,
+    remote: &mut HashMap<i32, String>,
+    task: task::Model,
+) -> Result<(), sea_orm::DbErr> {
+    let key = task.concurrency_key().to_string();
+    let dir = PathBuf::from(&task.dir);
+    let requirements = task.labels();
+    let local = (task.dir.is_empty() || state.settings.contains_key(&dir))
+        && workers::labels_match(
+            &requirements,
+            state.labels.iter().chain(&state.dir_settings(&dir).labels),
+        );
+    let blocking = active.iter().chain(remote.iter()).find(|(_, k)| **k == key);
+    let reason = if let Some((id, _)) = blocking {
+        format!("Waiting for task {id} with concurrency key {key}")
+    } else if !local {
+        if let Some(worker) = state.workers.assign(task.id, &dir, &key, &requirements) {
+            info!("Task {} dispatched to worker {}", task.id, worker);
+            remote.insert(task.id, key);
+            start_task(state, task.id, &worker).await?;
+            return Ok(());
+        }
+        let target = if requirements.is_empty() {
+            "worker".to_string()
+        } else {
+            format!("worker with labels {}", requirements.join(", "))
+        };
+        if state.workers.can_run(&dir, &requirements) {
+            format!("Waiting for a free {target} serving {}", task.dir)
+        } else {
+            format!("Waiting for a {target} serving {}", task.dir)
+        }
+    } else if active.len() >= state.max_parallel {
+        "Waiting for a free runner".to_string()
+    } else {
+        String::new()
+    };
+    if !reason.is_empty() {
+        if task.waiting_reason != reason {
+            task::set_waiting_reason(&state.conn, task.id, reason).await?;
+        }
+        return Ok(());
+    }
+    active.insert(task.id, key);
+    start_task(state, task.id, "").await?;
+    let state = state.clone();
+    let span = telemetry::execution_span(&task);
+    running.spawn(async move {
+        let id = task.id;
+        if let Err(err) = run_task(&state, task).instrument(span).await {
+            error!("Failed to run task {}: {}", id, err);
+        }
+        id
+    });
+    Ok(())
+}
+
+/// Builds the invocation of a command with the environment file of the directory and extra variables.
+pub fn task_invocation(
+    settings: &DirSettings,
+    work_dir: &std::path::Path,
+    command: &str,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> anyhow::Result<Invocation> {
+    let mut invocation = executor_for(&settings.executor).invocation(work_dir, command)?;
+    if let Some(env_file) = &settings.env_file {
+        let path = work_dir.join(env_file);
+        for item in dotenvy::from_path_iter(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?
         {
-            Ok(_) => {
-                info!("Task {} completed successfully", task.id);
-                update_task(state, task.id, task::TaskStatus::Success).await?;
-            }
-            Err(err) => {
-                error!("Task {} failed: {}", task.id, err);
-                update_task(state, task.id, task::TaskStatus::Failed).await?;
-            }
+            invocation.env.push(item?);
+        }
+    }
+    invocation.env.extend(env);
+    Ok(invocation)
+}
+
+/// Environment of a task with the secrets its recipe may use, and the secret values.
+async fn task_env(
+    state: &AppState,
+    task: &task::Model,
+) -> Result<(Vec<(String, String)>, Vec<String>), sea_orm::DbErr> {
+    let mut env = task.env().into_iter().collect::<Vec<_>>();
+    let secrets =
+        secret::secrets_for_recipe(&state.conn, &state.cipher, task::recipe_of(&task.command))
+            .await?;
+    let values = secrets.iter().map(|(_, value)| value.clone()).collect();
+    env.extend(secrets);
+    Ok((env, values))
+}
+
+async fn prepare_invocation(
+    state: &AppState,
+    settings: &DirSettings,
+    work_dir: &std::path::Path,
+    task: &task::Model,
+) -> anyhow::Result<Invocation> {
+    let (env, secrets) = task_env(state, task).await?;
+    let mut invocation = task_invocation(settings, work_dir, &task.command, env)?;
+    invocation.secrets = secrets;
+    Ok(invocation)
+}
+
+/// Runs a task in the container or sandbox of its directory.
+pub async fn execute_task(
+    settings: &DirSettings,
+    invocation: Invocation,
+    task: &task::Model,
+    work_dir: &std::path::Path,
+    output_dir: &std::path::Path,
+    log_file: &std::path::Path,
+    terminate: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    let container_name = container::container_name(task.id);
+    let invocation = if let Some(container) = &settings.container {
+        container.wrap(invocation, &container_name, work_dir, output_dir)
+    } else if let Some(sandbox) = &settings.process.sandbox {
+        sandbox.wrap(invocation, work_dir, output_dir)
+    } else {
+        invocation
+    };
+    let output_file = task.output.as_ref().map(|path| output_dir.join(path));
+    let result = run_invocation(
+        &invocation,
+        &settings.process,
+        work_dir,
+        log_file,
+        output_file.as_ref(),
+        terminate,
+    )
+    .await;
+    if let (Some(container), Err(err)) = (&settings.container, &result)
+        && err.kind() == std::io::ErrorKind::Interrupted
+    {
+        container.kill(&container_name).await;
+    }
+    result
+}
+
+async fn log_file(state: &AppState, task: &task::Model) -> PathBuf {
+    let log_dir = state.logs_dir.join(task.month());
+    tokio::fs::create_dir_all(&log_dir)
+        .await
+        .unwrap_or_else(|err| error!("Failed to create log directory: {}", err));
+    log_dir.join(format!("{}.log", task.id))
+}
+
+async fn run_task(state: &AppState, task: task::Model) -> Result<(), sea_orm::DbErr> {
+    info!("Running task: {}", task.id);
+    let log_file = log_file(state, &task).await;
+    let work_dir = if task.dir.is_empty() {
+        state.work_dir.read().unwrap().clone()
+    } else {
+        PathBuf::from(&task.dir)
+    };
+    let settings = state.dir_settings(&work_dir);
+    let result = match prepare_invocation(state, &settings, &work_dir, &task).await {
+        Ok(invocation) => {
+            let terminate = state.runner.terminate.subscribe();
+            execute_task(
+                &settings,
+                invocation,
+                &task,
+                &work_dir,
+                &state.output_dir,
+                &log_file,
+                terminate,
+            )
+            .await
+        }
+        Err(err) => {
+            let message = format!("{:#}", err);
+            tokio::fs::write(&log_file, &message)
+                .await
+                .unwrap_or_else(|err| error!("Failed to write log file: {}", err));
+            Err(std::io::Error::other(message))
+        }
+    };
+    match result {
+        Ok(_) => {
+            info!("Task {} completed successfully", task.id);
+            update_task(state, task.id, task::TaskStatus::Success).await?;
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {
+            info!("Task {} interrupted", task.id);
+            let status = state.shutdown_policy.interrupted_status();
+            update_task(state, task.id, status).await?;
+        }
+        Err(err) => {
+            error!("Task {} failed: {}", task.id, err);
+            update_task(state, task.id, task::TaskStatus::Failed).await?;
         }
     }
     Ok(())
 }
 
+fn user_name(user: Option<Extension<AuthUser>>) -> String {
+    user.map_or("anonymous".to_string(), |Extension(AuthUser(user))| user)
+}
+
+fn too_many_requests(retry_after: std::time::Duration, message: &str) -> Response {
+    let seconds = (retry_after.as_secs_f64().ceil() as u64).max(1);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, seconds.to_string())],
+        message.to_string(),
+    )
+        .into_response()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TaskRequest {
+    pub name: String,
+    pub command: String,
+    pub output: Option<String>,
+    pub concurrency: Option<String>,
+    pub dedup: Option<DedupPolicy>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Labels the machine running the task must have, like `["os=windows", "gpu=false"]`.
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+impl TaskRequest {
+    /// Whether a task was created from the same request, to answer a replayed `Idempotency-Key`.
+    fn matches(&self, task: &task::Model) -> bool {
+        task.name == self.name
+            && task.command == self.command
+            && task.output == self.output
+            && self
+                .concurrency
+                .as_ref()
+                .is_none_or(|key| *key == task.concurrency_key)
+            && task.env() == self.env
+            && task.labels() == self.labels
+    }
+}
+
+fn is_env_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Variables a submission may not set, they change which programs run or what they load.
+const PROTECTED_ENV: [&str; 18] = [
+    "PATH",
+    "IFS",
+    "ENV",
+    "BASH_ENV",
+    "SHELLOPTS",
+    "BASHOPTS",
+    "PS4",
+    "PROMPT_COMMAND",
+    "APP_SECRET",
+    "NODE_OPTIONS",
+    "PYTHONPATH",
+    "PYTHONSTARTUP",
+    "PYTHONHOME",
+    "PERL5OPT",
+    "PERL5LIB",
+    "RUBYOPT",
+    "JAVA_TOOL_OPTIONS",
+    "_JAVA_OPTIONS",
+];
+/// Prefixes of protected variables, like `LD_PRELOAD` and exported bash functions.
+const PROTECTED_ENV_PREFIXES: [&str; 3] = ["LD_", "DYLD_", "BASH_FUNC_"];
+
+fn is_protected_env(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    PROTECTED_ENV.contains(&name.as_str())
+        || PROTECTED_ENV_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
 pub async fn add_task(
     state: State<AppState>,
-    Json(mut payload): Json<HashMap<String, String>>,
-) -> Result<Json<task::Model>, (StatusCode, String)> {
-    let name = payload
-        .remove("name")
-        .ok_or_else(|| (StatusCode::BAD_REQUEST, "name is required".to_string()))?;
-    let command = payload
-        .remove("command")
-        .ok_or_else(|| (StatusCode::BAD_REQUEST, "command is required".to_string()))?;
-    let output = payload.remove("output");
-    let work_dir = state.work_dir.read().unwrap().to_str().unwrap().to_string().clone();
-    let task = task::create_task(&state.conn, work_dir, name, command, output)
+    user: Option<Extension<AuthUser>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<TaskRequest>,
+) -> Result<Json<task::Model>, Response> {
+    if user.is_none() {
+        tracing::Span::current().record("user", "anonymous");
+    }
+    let user = user_name(user);
+    if let Some(key) = payload.env.keys().find(|key| !is_env_name(key)) {
+        let message = format!("Invalid environment variable name: {key}");
+        return Err((StatusCode::BAD_REQUEST, message).into_response());
+    }
+    if let Some(key) = payload.env.keys().find(|key| is_protected_env(key)) {
+        let message = format!("Environment variable {key} cannot be set by a task");
+        return Err((StatusCode::BAD_REQUEST, message).into_response());
+    }
+    if let Some(label) = payload.labels.iter().find(|label| !workers::is_label(label)) {
+        let message = format!("Invalid label requirement: {label}");
+        return Err((StatusCode::BAD_REQUEST, message).into_response());
+    }
+    if let Some(output) = &payload.output {
+        output_path(&state.output_dir, output).map_err(IntoResponse::into_response)?;
+    }
+    let idempotency_key = headers
+        .get("Idempotency-Key")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let work_dir = state.work_dir.read().unwrap().clone();
+    let settings = state.dir_settings(&work_dir);
+    let work_dir = work_dir.to_str().unwrap().to_string();
+
+    // Only valid submissions count against the rate limits.
+    let limits = &state.limiter.limits;
+    let user_key = format!("user:{user}");
+    let ip_key = format!("ip:{}", addr.ip());
+    state
+        .limiter
+        .acquire(&[
+            (&user_key, limits.user_per_minute),
+            (&ip_key, limits.ip_per_minute),
+        ])
+        .map_err(|wait| too_many_requests(wait, "Too many submissions, try again later"))?;
+
+    let db_error = |err: sea_orm::DbErr| {
+        (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+    };
+    let _guard = state.submit_lock.lock().await;
+    if !idempotency_key.is_empty()
+        && let Some(task) = task::find_by_idempotency_key(&state.conn, &user, &idempotency_key)
+            .await
+            .map_err(db_error)?
+    {
+        if !payload.matches(&task) {
+            let message = format!(
+                "Idempotency-Key was already used for task {} with a different request",
+                task.id
+            );
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, message).into_response());
+        }
+        return Ok(Json(task));
+    }
+    let TaskRequest {
+        name,
+        command,
+        output,
+        concurrency,
+        dedup,
+        env,
+        labels,
+    } = payload;
+    let recipe_settings = settings.recipe(task::recipe_of(&command));
+    let dedup = dedup
+        .or(recipe_settings.dedup)
+        .unwrap_or(state.dedup_policy);
+    if dedup != DedupPolicy::Queue
+        && let Some(task) = task::find_active(&state.conn, &work_dir, &command, &env, &labels)
+            .await
+            .map_err(db_error)?
+    {
+        return match dedup {
+            DedupPolicy::Reject => Err((
+                StatusCode::CONFLICT,
+                format!("The same command is already queued as task {}", task.id),
+            )
+                .into_response()),
+            _ => Ok(Json(task)),
+        };
+    }
+    if let Some(limit) = limits.max_pending_per_user {
+        let pending = task::pending_count_by_submitter(&state.conn, &user)
+            .await
+            .map_err(db_error)?;
+        if pending >= limit {
+            return Err(too_many_requests(QUOTA_RETRY_AFTER, "Too many pending tasks"));
+        }
+    }
+    if let Some(limit) = limits.max_identical_pending {
+        let pending = task::pending_count_by_command(&state.conn, &work_dir, &command)
+            .await
+            .map_err(db_error)?;
+        if pending >= limit {
+            return Err(too_many_requests(
+                QUOTA_RETRY_AFTER,
+                "The same command is already pending",
+            ));
+        }
+    }
+
+    let concurrency_key = concurrency
+        .or(recipe_settings.concurrency)
+        .unwrap_or_else(|| work_dir.clone());
+    let (trace_id, span_id, trace_flags) = telemetry::current_ids();
+    let new_task = task::NewTask {
+        dir: work_dir,
+        name,
+        command,
+        output,
+        submitter: user,
+        idempotency_key,
+        concurrency_key,
+        env,
+        labels,
+        trace_id,
+        span_id,
+        trace_flags,
+    };
+    let task = task::create_task(&state.conn, new_task)
         .await
-        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
-    CHECKING.store(true, Ordering::SeqCst);
+        .map_err(db_error)?;
+    logging::record_task(&task);
+    info!("Task {} added: {}", task.id, task.command);
+    state.runner.wake();
     Ok(Json(task))
 }
 
+#[derive(Clone, Debug, Serialize)]
+pub struct QuotaInfo {
+    pub user: String,
+    pub ip: String,
+    pub user_requests_per_minute: Usage,
+    pub ip_requests_per_minute: Usage,
+    pub pending_tasks: Usage,
+    pub max_identical_pending: Option<u64>,
+}
+
+pub async fn get_quota(
+    state: State<AppState>,
+    user: Option<Extension<AuthUser>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Result<Json<QuotaInfo>, (StatusCode, String)> {
+    let user = user_name(user);
+    let ip = addr.ip().to_string();
+    let limits = &state.limiter.limits;
+    let pending = task::pending_count_by_submitter(&state.conn, &user)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(QuotaInfo {
+        user_requests_per_minute: Usage {
+            used: state.limiter.used(&format!("user:{user}")),
+            limit: limits.user_per_minute.map(|limit| limit as u64),
+        },
+        ip_requests_per_minute: Usage {
+            used: state.limiter.used(&format!("ip:{ip}")),
+            limit: limits.ip_per_minute.map(|limit| limit as u64),
+        },
+        pending_tasks: Usage {
+            used: pending,
+            limit: limits.max_pending_per_user,
+        },
+        max_identical_pending: limits.max_identical_pending,
+        user,
+        ip,
+    }))
+}
+
 pub async fn cancel_task(
     state: State<AppState>,
     Path(id): Path<i32>,
 ) -> Result<String, (StatusCode, String)> {
+    tracing::Span::current().record("task_id", id);
     task::delete_task(&state.conn, id)
         .await
         .map(|value| value.to_string())
         .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct SecretRequest {
+    pub value: String,
+    /// Recipe names or glob patterns which receive the secret.
+    #[serde(default)]
+    pub recipes: Vec<String>,
+}
+
+pub async fn list_secrets(
+    state: State<AppState>,
+) -> Result<Json<Vec<secret::Model>>, (StatusCode, String)> {
+    secret::list_secrets(&state.conn)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+pub async fn save_secret(
+    state: State<AppState>,
+    Path(name): Path<String>,
+    Json(payload): Json<SecretRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !is_env_name(&name) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Secret name must be a valid environment variable name".to_string(),
+        ));
+    }
+    if is_protected_env(&name) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Environment variable {name} cannot be set by a secret"),
+        ));
+    }
+    secret::save_secret(
+        &state.conn,
+        &state.cipher,
+        name,
+        &payload.value,
+        payload.recipes,
+    )
+    .await
+    .map(|_| StatusCode::NO_CONTENT)
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+pub async fn delete_secret(
+    state: State<AppState>,
+    Path(name): Path<String>,
+) -> Result<String, (StatusCode, String)> {
+    secret::delete_secret(&state.conn, &name)
+        .await
+        .map(|value| value.to_string())
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
 pub async fn reset_task(
     state: State<AppState>,
     Path(id): Path<i32>,
@@ -176,7 +895,8 @@ pub async fn reset_task(
     let task = update_task(&state, id, task::TaskStatus::Pending)
         .await
         .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
-    CHECKING.store(true, Ordering::SeqCst);
+    logging::record_task(&task);
+    state.runner.wake();
     Ok(Json(task))
 }
 
@@ -187,13 +907,70 @@ pub async fn update_task(
     status: task::TaskStatus,
 ) -> Result<task::Model, sea_orm::DbErr> {
     let task = task::update_task(&state.conn, id, status).await?;
+    send_status(state, id, status);
+    if status.is_finished() {
+        state.metrics.task_finished(&task);
+        telemetry::record_task(&task);
+        if let Some(finished) = &state.finished {
+            let _ = finished.send(task.clone());
+        }
+        index_log(state, &task).await;
+    }
+    Ok(task)
+}
+
+/// Reads the start and the end of a log, together at most `MAX_INDEXED_LOG` bytes,
+/// so huge logs do not have to fit in memory.
+async fn read_log_for_index(path: &std::path::Path) -> std::io::Result<String> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    let mut file = tokio::fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    let mut content = Vec::new();
+    if len <= MAX_INDEXED_LOG {
+        file.read_to_end(&mut content).await?;
+    } else {
+        let half = MAX_INDEXED_LOG / 2;
+        (&mut file).take(half).read_to_end(&mut content).await?;
+        content.extend_from_slice(b"\n...\n");
+        file.seek(std::io::SeekFrom::Start(len - half)).await?;
+        file.take(half).read_to_end(&mut content).await?;
+    }
+    Ok(String::from_utf8_lossy(&content).to_string())
+}
+
+/// Adds the log of a finished task to the search index.
+async fn index_log(state: &AppState, task: &task::Model) {
+    let path = log_file(state, task).await;
+    let content = match read_log_for_index(&path).await {
+        Ok(content) => content,
+        Err(err) => {
+            warn!("Failed to read log of task {} for indexing: {}", task.id, err);
+            return;
+        }
+    };
+    if let Err(err) = task::index_log(&state.conn, task.id, content).await {
+        error!("Failed to index log of task {}: {}", task.id, err);
+    }
+}
+
+async fn start_task(
+    state: &AppState,
+    id: i32,
+    worker: &str,
+) -> Result<task::Model, sea_orm::DbErr> {
+    let task = task::start_task(&state.conn, id, worker).await?;
+    send_status(state, id, task::TaskStatus::Running);
+    state.metrics.task_started(&task);
+    Ok(task)
+}
+
+fn send_status(state: &AppState, id: i32, status: task::TaskStatus) {
     let event = TaskStatusEvent {
         task_id: id,
         status: format!("{:?}", status),
         timestamp: chrono::Local::now().to_rfc3339(),
     };
     let _ = state.sender.send(event);
-    Ok(task)
 }
 
 // SSE endpoint for task status updates
@@ -236,49 +1013,581 @@ pub async fn task_status_sse(
     Sse::new(combined)
 }
 
+/// Query of `GET /list/{page}` and `GET /list`, all filters are optional.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ListQuery {
+    /// Comma separated statuses, like `failed,interrupted`.
+    pub status: Option<String>,
+    pub name: Option<String>,
+    pub command: Option<String>,
+    pub dir: Option<String>,
+    pub submitter: Option<String>,
+    /// RFC 3339 time or `YYYY-MM-DD` date.
+    pub from: Option<String>,
+    /// RFC 3339 time, or `YYYY-MM-DD` date which is included.
+    pub to: Option<String>,
+    pub page_size: Option<u64>,
+    pub sort: Option<task::SortField>,
+    pub order: Option<task::SortOrder>,
+    /// Cursor of `GET /list`, the tasks after it are returned.
+    pub after: Option<String>,
+    /// Cursor of `GET /list`, the tasks changed or deleted after it are returned,
+    /// oldest change first.
+    pub since: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskPage {
+    pub tasks: Vec<task::Model>,
+    pub page: u64,
+    pub page_size: u64,
+    pub pages: u64,
+    pub total: u64,
+}
+
+/// Result of `GET /list`.
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskCursorPage {
+    pub tasks: Vec<task::Model>,
+    /// Cursor of the last task, or the requested one when no task is returned.
+    pub cursor: Option<String>,
+    /// Whether more tasks follow the cursor.
+    pub more: bool,
+    /// Ids of the tasks deleted after the `since` cursor, whatever the filter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<Vec<i32>>,
+}
+
+const DEFAULT_PAGE_SIZE: u64 = 10;
+const MAX_PAGE_SIZE: u64 = 100;
+
+/// Parses a time of the list query, a date means its start, or the end when `end` is set.
+fn parse_time(value: &str, end: bool) -> Result<time::OffsetDateTime, String> {
+    use time::format_description::well_known::Rfc3339;
+    if let Ok(time) = time::OffsetDateTime::parse(value, &Rfc3339) {
+        return Ok(time);
+    }
+    let invalid = || format!("Invalid time: {value}, use RFC 3339 or YYYY-MM-DD");
+    let parts = value
+        .split('-')
+        .map(|part| part.parse::<i32>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    let month = time::Month::try_from(month as u8).map_err(|_| invalid())?;
+    let date = time::Date::from_calendar_date(year, month, day as u8).map_err(|_| invalid())?;
+    let date = if end {
+        date.next_day().ok_or_else(invalid)?
+    } else {
+        date
+    };
+    Ok(date.midnight().assume_utc())
+}
+
+impl ListQuery {
+    fn page_size(&self) -> Result<u64, (StatusCode, String)> {
+        let page_size = self.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+        if page_size == 0 || page_size > MAX_PAGE_SIZE {
+            let message = format!("Page size must be between 1 and {MAX_PAGE_SIZE}");
+            return Err((StatusCode::BAD_REQUEST, message));
+        }
+        Ok(page_size)
+    }
+
+    fn filter(&self) -> Result<task::TaskFilter, String> {
+        let statuses = self
+            .status
+            .iter()
+            .flat_map(|status| status.split(','))
+            .filter(|status| !status.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(task::TaskFilter {
+            statuses,
+            name: self.name.clone(),
+            command: self.command.clone(),
+            dir: self.dir.clone(),
+            submitter: self.submitter.clone(),
+            from: self.from.as_deref().map(|from| parse_time(from, false)).transpose()?,
+            to: self.to.as_deref().map(|to| parse_time(to, true)).transpose()?,
+        })
+    }
+}
+
 pub async fn list_task(
     state: State<AppState>,
     Path(page): Path<u64>,
-) -> Result<Json<(Vec<task::Model>, u64)>, (StatusCode, String)> {
+    Query(query): Query<ListQuery>,
+) -> Result<Json<TaskPage>, (StatusCode, String)> {
     if page == 0 {
         return Err((
             StatusCode::BAD_REQUEST,
             "Page number must be greater than 0".to_string(),
         ));
     }
-    let (tasks, pages) = task::recent_tasks(&state.conn, 10, page - 1)
+    if query.after.is_some() || query.since.is_some() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Cursors are only accepted by GET /list".to_string(),
+        ));
+    }
+    let page_size = query.page_size()?;
+    let filter = query
+        .filter()
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+    let (tasks, pages, total) = task::list_tasks(
+        &state.conn,
+        &filter,
+        query.sort.unwrap_or_default(),
+        query.order.unwrap_or_default(),
+        page_size,
+        page - 1,
+    )
+    .await
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(TaskPage {
+        tasks,
+        page,
+        page_size,
+        pages,
+        total,
+    }))
+}
+
+/// Lists tasks with keyset pagination, which needs no count and does not shift when
+/// tasks are added. `since` returns the tasks changed after a cursor to keep a copy in sync.
+pub async fn list_task_after(
+    state: State<AppState>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<TaskCursorPage>, (StatusCode, String)> {
+    let bad_request = |message: String| (StatusCode::BAD_REQUEST, message);
+    let page_size = query.page_size()?;
+    let filter = query.filter().map_err(bad_request)?;
+    let parse = |cursor: &String| cursor.parse::<task::Cursor>().map_err(bad_request);
+    let (sort, order, cursor) = match (&query.after, &query.since) {
+        (Some(_), Some(_)) => {
+            return Err(bad_request("Use either after or since".to_string()));
+        }
+        (None, Some(since)) => {
+            if query.sort.is_some() || query.order.is_some() {
+                let message = "since always returns the oldest change first".to_string();
+                return Err(bad_request(message));
+            }
+            let since = since
+                .parse::<i64>()
+                .map_err(|_| bad_request(format!("invalid cursor: {since}")))?;
+            return list_changes(&state, &filter, since, page_size).await.map(Json);
+        }
+        (after, None) => {
+            let sort = query.sort.unwrap_or_default();
+            if !matches!(sort, task::SortField::Id | task::SortField::UpdatedAt) {
+                let message = "Cursors only sort by id or updated_at".to_string();
+                return Err(bad_request(message));
+            }
+            let cursor = after.as_ref().map(parse).transpose()?;
+            if cursor.is_some_and(|cursor| cursor.sort() != sort) {
+                let message = "The cursor does not match the sort field".to_string();
+                return Err(bad_request(message));
+            }
+            (sort, query.order.unwrap_or_default(), cursor)
+        }
+    };
+    // One more task tells whether another page follows.
+    let mut tasks =
+        task::list_tasks_after(&state.conn, &filter, sort, order, cursor, page_size + 1)
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let more = tasks.len() as u64 > page_size;
+    tasks.truncate(page_size as usize);
+    let cursor = tasks
+        .last()
+        .map(|task| task::Cursor::of(task, sort))
+        .or(cursor)
+        .map(|cursor| cursor.to_string());
+    Ok(Json(TaskCursorPage {
+        tasks,
+        cursor,
+        more,
+        deleted: None,
+    }))
+}
+
+/// Page of a `since` sync. Its cursor is the sequence number of the last change, which
+/// follows the commit order, so a change committed late is not skipped.
+async fn list_changes(
+    state: &AppState,
+    filter: &task::TaskFilter,
+    since: i64,
+    page_size: u64,
+) -> Result<TaskCursorPage, (StatusCode, String)> {
+    let (tasks, deleted) = task::changes_since(&state.conn, filter, since, page_size + 1)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let mut changes = tasks
+        .into_iter()
+        .map(|task| (task.seq, Some(task), None))
+        .chain(deleted.into_iter().map(|(id, seq)| (seq, None, Some(id))))
+        .collect::<Vec<_>>();
+    changes.sort_by_key(|(seq, _, _)| *seq);
+    let more = changes.len() as u64 > page_size;
+    changes.truncate(page_size as usize);
+    let cursor = changes.last().map_or(since, |(seq, _, _)| *seq);
+    let (tasks, deleted) = changes
+        .into_iter()
+        .map(|(_, task, id)| (task, id))
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+    Ok(TaskCursorPage {
+        tasks: tasks.into_iter().flatten().collect(),
+        cursor: Some(cursor.to_string()),
+        more,
+        deleted: Some(deleted.into_iter().flatten().collect()),
+    })
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DetailQuery {
+    /// Lines at the end of the log to return, default 20.
+    pub lines: Option<usize>,
+}
+
+/// A file produced by a task, downloadable from `url`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Artifact {
+    pub path: String,
+    pub url: String,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PreviousRun {
+    pub id: i32,
+    pub status: task::TaskStatus,
+    pub duration_secs: Option<f64>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub finished_at: Option<time::OffsetDateTime>,
+}
+
+/// Result of `GET /task/{id}`.
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskDetail {
+    #[serde(flatten)]
+    pub task: task::Model,
+    pub log: String,
+    /// Size of the log in bytes, missing before the task writes one.
+    pub log_size: Option<u64>,
+    pub log_tail: Vec<String>,
+    pub artifacts: Vec<Artifact>,
+    pub duration_secs: Option<f64>,
+    /// Last finished run of the same command in the same directory.
+    pub previous: Option<PreviousRun>,
+}
+
+const DEFAULT_TAIL_LINES: usize = 20;
+const MAX_TAIL_LINES: usize = 1000;
+
+/// Reads the last lines of a file from its end, so large logs are not read as a whole.
+fn tail_lines(path: &std::path::Path, count: usize) -> std::io::Result<Vec<String>> {
+    use std::io::{Read, Seek, SeekFrom};
+    const BLOCK: u64 = 8192;
+    let mut file = std::fs::File::open(path)?;
+    let mut end = file.metadata()?.len();
+    let mut tail = Vec::new();
+    // A trailing newline ends the last line rather than starting an empty one.
+    while end > 0 && tail.iter().filter(|&&b| b == b'\n').count() <= count {
+        let start = end.saturating_sub(BLOCK);
+        let mut block = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block)?;
+        block.extend(tail);
+        tail = block;
+        end = start;
+    }
+    let text = String::from_utf8_lossy(&tail);
+    let lines = text.lines().collect::<Vec<_>>();
+    let skip = lines.len().saturating_sub(count);
+    Ok(lines[skip..].iter().map(|line| line.to_string()).collect())
+}
+
+fn duration_secs(task: &task::Model) -> Option<f64> {
+    task.duration().map(|duration| duration.as_seconds_f64())
+}
+
+/// Returns a task with its log tail, artifacts, duration and previous run.
+pub async fn get_task(
+    state: State<AppState>,
+    Path(id): Path<i32>,
+    Query(query): Query<DetailQuery>,
+) -> Result<Json<TaskDetail>, (StatusCode, String)> {
+    let db_error = |err: sea_orm::DbErr| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    let task = task::get_task(&state.conn, id)
+        .await
+        .map_err(db_error)?
+        .ok_or((StatusCode::NOT_FOUND, format!("Task {id} not found")))?;
+    logging::record_task(&task);
+    let lines = query
+        .lines
+        .unwrap_or(DEFAULT_TAIL_LINES)
+        .min(MAX_TAIL_LINES);
+    let log_path = state
+        .logs_dir
+        .join(task.month())
+        .join(format!("{}.log", task.id));
+    let io_error = |err: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    let log_size = tokio::fs::metadata(&log_path).await.ok().map(|meta| meta.len());
+    let log_tail = match log_size {
+        Some(_) if lines > 0 => tokio::task::spawn_blocking(move || tail_lines(&log_path, lines))
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+            .map_err(io_error)?,
+        _ => Vec::new(),
+    };
+    let mut artifacts = Vec::new();
+    // Outputs stored before they were validated may point outside of OUTPUT_DIR.
+    if let Some(output) = &task.output
+        && let Ok(path) = output_path(&state.output_dir, output)
+        && let Ok(meta) = tokio::fs::metadata(path).await
+        && meta.is_file()
+    {
+        artifacts.push(Artifact {
+            path: output.clone(),
+            url: package_url(output),
+            size: meta.len(),
+        });
+    }
+    let previous = task::previous_run(&state.conn, &task)
+        .await
+        .map_err(db_error)?
+        .map(|previous| PreviousRun {
+            id: previous.id,
+            status: previous.status,
+            duration_secs: duration_secs(&previous),
+            finished_at: previous.finished_at,
+        });
+    Ok(Json(TaskDetail {
+        log: format!("/logs/{}/{}.log", task.month(), task.id),
+        log_size,
+        log_tail,
+        artifacts,
+        duration_secs: duration_secs(&task),
+        previous,
+        task,
+    }))
+}
+
+/// Serves the metrics in the Prometheus text format.
+pub async fn get_metrics(state: State<AppState>) -> Result<Response, (StatusCode, String)> {
+    let queue = task::count_by_status(&state.conn)
         .await
         .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
-    Ok(Json((tasks, pages)))
+    let body = state.metrics.render(&queue, state.sender.receiver_count());
+    let content_type = "text/plain; version=0.0.4; charset=utf-8";
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
 }
 
-pub async fn get_available(
+#[derive(Clone, Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<health::Check>,
+}
+
+/// Answers as long as the process serves requests.
+pub async fn healthz() -> Json<serde_json::Value> {
+    Json(serde_json::json!({"status": "ok"}))
+}
+
+/// Checks the database, the runner, the executors and the directories, answering 503 when
+/// any check fails. Only users see the directories and error messages.
+pub async fn readyz(
     state: State<AppState>,
-) -> Result<Json<Vec<String>>, (StatusCode, String)> {
-    let work_dir = state.work_dir.clone();
-    let output = tokio::task::spawn_blocking(move || {
-        let work_dir = work_dir.read().unwrap();
-        Command::new("just")
-            .current_dir(work_dir.as_path())
-            .arg("--list")
-            .output()
-    })
-    .await
-    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
-    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
-    if output.status.success() {
-        Ok(Json(
-            String::from_utf8_lossy(&output.stdout)
-                .lines()
-                .skip(1) // skip "Available recipes:"
-                .map(|line| line.trim().to_string())
-                .collect::<Vec<_>>(),
-        ))
+    user: Option<Extension<AuthUser>>,
+) -> (StatusCode, Json<Readiness>) {
+    let mut checks = Vec::new();
+    let database = state
+        .conn
+        .ping()
+        .await
+        .map(|_| String::new())
+        .map_err(|err| err.to_string());
+    checks.push(health::Check::new("database", None, database));
+    // The runner looks for tasks at least every poll timeout even when idle.
+    let since_tick = state.runner.since_tick();
+    let runner = if state.runner.is_stopping() {
+        Err("stopping".to_string())
+    } else if since_tick > workers::POLL_TIMEOUT * 2 {
+        Err(format!("last ran {}s ago", since_tick.as_secs()))
     } else {
-        Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            String::from_utf8_lossy(&output.stderr).to_string(),
-        ))
+        Ok(String::new())
+    };
+    checks.push(health::Check::new("runner", None, runner));
+    let mut dirs = state.settings.iter().collect::<Vec<_>>();
+    dirs.sort_by_key(|(dir, _)| *dir);
+    for (dir, settings) in &dirs {
+        checks.push(health::executor(dir, settings).await);
+    }
+    for (dir, _) in &dirs {
+        checks.push(health::writable(dir).await);
+    }
+    checks.push(health::writable(&state.output_dir).await);
+    checks.push(health::disk_space(&state.logs_dir, state.min_free_disk_mb).await);
+    let ready = checks.iter().all(|check| check.ok);
+    if user.is_none() {
+        for check in &mut checks {
+            check.target = None;
+            check.message.clear();
+        }
+    }
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(Readiness { ready, checks }))
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct StatsQuery {
+    /// Days to cover including today, default 30.
+    pub days: Option<u32>,
+    pub dir: Option<String>,
+    pub recipe: Option<String>,
+}
+
+const DEFAULT_STATS_DAYS: u32 = 30;
+const MAX_STATS_DAYS: u32 = 366;
+
+/// Aggregates the tasks finished in the last days per recipe and directory.
+pub async fn get_stats(
+    state: State<AppState>,
+    Query(query): Query<StatsQuery>,
+) -> Result<Json<Vec<stats::RecipeStats>>, (StatusCode, String)> {
+    let days = query.days.unwrap_or(DEFAULT_STATS_DAYS);
+    if days == 0 || days > MAX_STATS_DAYS {
+        let message = format!("Days must be between 1 and {MAX_STATS_DAYS}");
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+    let today = time::OffsetDateTime::now_utc().date();
+    let from = today - time::Duration::days(i64::from(days) - 1);
+    let db_error = |err: sea_orm::DbErr| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    let dir = query.dir.as_deref();
+    let recipe = query.recipe.as_deref();
+    let runs = task::finished_runs(&state.conn, from, dir, recipe)
+        .await
+        .map_err(db_error)?;
+    let last_runs = task::last_runs(&state.conn, from, dir, recipe)
+        .await
+        .map_err(db_error)?;
+    let ids = last_runs.iter().map(|(.., id)| *id).collect::<Vec<_>>();
+    let tasks = task::tasks_by_ids(&state.conn, &ids)
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(|task| (task.id, task))
+        .collect::<HashMap<_, _>>();
+    let last = last_runs
+        .into_iter()
+        .filter_map(|(dir, recipe, status, id)| Some(((dir, recipe, status), tasks.get(&id)?)))
+        .collect();
+    Ok(Json(stats::aggregate(&runs, last)))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchHit {
+    pub id: i32,
+    pub name: String,
+    pub command: String,
+    pub status: task::TaskStatus,
+    /// Log text around the matches, HTML escaped with the matches in `<mark>` tags.
+    pub snippet: String,
+    pub log: String,
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Searches the logs of finished tasks for all words of `q`.
+pub async fn search_logs(
+    state: State<AppState>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchHit>>, (StatusCode, String)> {
+    if query.q.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Query must not be empty".to_string()));
+    }
+    let limit = query.limit.unwrap_or(20).min(MAX_PAGE_SIZE);
+    let db_error = |err: sea_orm::DbErr| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    let matches = task::search_logs(&state.conn, &query.q, limit)
+        .await
+        .map_err(db_error)?;
+    let ids = matches.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    let tasks = task::tasks_by_ids(&state.conn, &ids)
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(|task| (task.id, task))
+        .collect::<HashMap<_, _>>();
+    let hits = matches
+        .into_iter()
+        .filter_map(|(id, snippet)| {
+            let task = tasks.get(&id)?;
+            let snippet = escape_html(&snippet)
+                .replace(task::MATCH_START, "<mark>")
+                .replace(task::MATCH_END, "</mark>");
+            Some(SearchHit {
+                id,
+                name: task.name.clone(),
+                command: task.command.clone(),
+                status: task.status,
+                snippet,
+                log: format!("/logs/{}/{}.log", task.month(), id),
+            })
+        })
+        .collect();
+    Ok(Json(hits))
+}
+
+pub async fn get_available(
+    state: State<AppState>,
+) -> Result<Json<Vec<String>>, (StatusCode, Json<serde_json::Value>)> {
+    let work_dir = state.work_dir.read().unwrap().clone();
+    if !state.settings.contains_key(&work_dir)
+        && let Some(recipes) = state.workers.recipes(&work_dir)
+    {
+        return Ok(Json(recipes));
+    }
+    let settings = state.dir_settings(&work_dir).executor;
+    let executor = executor_for(&settings);
+    let dir = work_dir.clone();
+    let result = tokio::task::spawn_blocking(move || executor.list(&dir))
+        .await
+        .unwrap_or_else(|err| Err(err.into()));
+    let mut errors = state.executor_errors.write().unwrap();
+    match result {
+        Ok(targets) => {
+            errors.remove(&work_dir);
+            Ok(Json(targets))
+        }
+        Err(err) => {
+            let message = format!("{:#}", err);
+            errors.insert(work_dir.clone(), message.clone());
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({
+                    "dir": work_dir,
+                    "executor": settings.kind,
+                    "error": format!("{:?} executor is not usable: {}", settings.kind, message),
+                })),
+            ))
+        }
     }
 }
 
@@ -286,9 +1595,15 @@ pub async fn get_dir(
     state: State<AppState>,
 ) -> Json<DirInfo> {
     let current = state.work_dir.read().unwrap().to_str().unwrap().to_string();
-    let all_dirs = state.work_dirs.iter()
+    let mut all_dirs = state.work_dirs.iter()
         .map(|d| d.to_str().unwrap().to_string())
         .collect::<Vec<_>>();
+    for dir in state.workers.dirs() {
+        let dir = dir.to_string_lossy().to_string();
+        if !all_dirs.contains(&dir) {
+            all_dirs.push(dir);
+        }
+    }
     Json(DirInfo { current, all_dirs })
 }
 
@@ -302,65 +1617,396 @@ pub async fn change_dir(
     Query(param): Query<ChangeDirParam>
 ) -> Redirect {
     let dir = PathBuf::from(param.dir);
-    if state.work_dirs.contains(&dir) {
+    if state.work_dirs.contains(&dir) || state.workers.serves(&dir) {
         let mut w = state.work_dir.write().unwrap();
         *w = dir;
     }
     Redirect::to("/")
 }
 
-pub async fn run_just_task(
-    command: &str,
+pub async fn register_worker(
+    state: State<AppState>,
+    agent: Option<Extension<AgentIdentity>>,
+    Json(registration): Json<Registration>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_agent(agent, &registration.name)?;
+    let db_error = |err: sea_orm::DbErr| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    let name = registration.name.clone();
+    let mut resumed = HashMap::new();
+    // Tasks running on a previous run of the agent are gone, unless the agent still runs
+    // them because the server restarted
+    for task in task::running_tasks_on_worker(&state.conn, &name)
+        .await
+        .map_err(db_error)?
+    {
+        if registration.running.contains(&task.id) {
+            resumed.insert(task.id, task.concurrency_key().to_string());
+            continue;
+        }
+        let span = logging::task_span(&task);
+        async {
+            warn!("Task {} was lost when worker {} restarted", task.id, name);
+            update_task(&state, task.id, task::TaskStatus::Interrupted).await
+        }
+        .instrument(span)
+        .await
+        .map_err(db_error)?;
+    }
+    state.workers.register(registration, resumed);
+    info!("Worker {} registered", name);
+    state.runner.wake();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_workers(state: State<AppState>) -> Json<Vec<WorkerStatus>> {
+    Json(state.workers.list())
+}
+
+/// Long poll of an agent, returns the next task dispatched to it or `204 No Content`.
+pub async fn poll_task(
+    state: State<AppState>,
+    agent: Option<Extension<AgentIdentity>>,
+    Query(query): Query<WorkerQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    check_agent(agent, &query.name)?;
+    let db_error = |err: sea_orm::DbErr| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    let deadline = tokio::time::Instant::now() + workers::POLL_TIMEOUT;
+    let mut shutdown = state.shutdown_tx.subscribe();
+    loop {
+        if state.runner.is_stopping() {
+            let message = "Server is shutting down".to_string();
+            return Err((StatusCode::SERVICE_UNAVAILABLE, message));
+        }
+        let notify = match state.workers.poll(&query.name) {
+            None => {
+                let message = format!("Worker {} is not registered", query.name);
+                return Err((StatusCode::NOT_FOUND, message));
+            }
+            Some(Ok(id)) => {
+                let Some(task) = task::get_task(&state.conn, id).await.map_err(db_error)? else {
+                    state.workers.finish(&query.name, id);
+                    continue;
+                };
+                logging::record_task(&task);
+                let (env, secrets) = task_env(&state, &task).await.map_err(db_error)?;
+                return Ok(Json(AgentJob { task, env, secrets }).into_response());
+            }
+            Some(Err(notify)) => notify,
+        };
+        tokio::select! {
+            _ = notify.notified() => {}
+            _ = tokio::time::sleep_until(deadline) => return Ok(StatusCode::NO_CONTENT.into_response()),
+            _ = shutdown.recv() => return Ok(StatusCode::NO_CONTENT.into_response()),
+        }
+    }
+}
+
+/// Finds a task running on the worker which sent the request.
+async fn worker_task(
+    state: &AppState,
+    id: i32,
+    worker: &str,
+) -> Result<task::Model, (StatusCode, String)> {
+    let task = task::get_task(&state.conn, id)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "Task not found".to_string()))?;
+    logging::record_task(&task);
+    if task.worker != worker || task.status != task::TaskStatus::Running {
+        let message = format!("Task {id} is not running on worker {worker}");
+        return Err((StatusCode::CONFLICT, message));
+    }
+    Ok(task)
+}
+
+pub async fn upload_log(
+    state: State<AppState>,
+    agent: Option<Extension<AgentIdentity>>,
+    Path(id): Path<i32>,
+    Query(query): Query<WorkerQuery>,
+    chunk: Bytes,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_agent(agent, &query.name)?;
+    let task = worker_task(&state, id, &query.name).await?;
+    use tokio::io::AsyncWriteExt;
+    let io_error = |err: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file(&state, &task).await)
+        .await
+        .map_err(io_error)?;
+    file.write_all(&chunk).await.map_err(io_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Path of the output of a task inside `OUTPUT_DIR`. Absolute paths and `..` are
+/// rejected, so a task cannot write or expose files elsewhere.
+pub fn output_path(
+    output_dir: &std::path::Path,
+    output: &str,
+) -> Result<PathBuf, (StatusCode, String)> {
+    let path = std::path::Path::new(output);
+    let valid = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if output.is_empty() || !valid {
+        let message = format!("Invalid output path: {output}");
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+    Ok(output_dir.join(path))
+}
+
+/// Characters escaped in a segment of a package path.
+const PATH_SEGMENT: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Link of an output below `/package`, each path segment percent-encoded.
+pub fn package_url(output: &str) -> String {
+    let path: Vec<String> = output
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .map(|segment| percent_encoding::utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect();
+    format!("/package/{}", path.join("/"))
+}
+
+pub async fn upload_artifact(
+    state: State<AppState>,
+    agent: Option<Extension<AgentIdentity>>,
+    Path(id): Path<i32>,
+    Query(query): Query<WorkerQuery>,
+    body: Body,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_agent(agent, &query.name)?;
+    let task = worker_task(&state, id, &query.name).await?;
+    let Some(output) = &task.output else {
+        return Err((StatusCode::BAD_REQUEST, "Task has no output".to_string()));
+    };
+    let io_error = |err: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    let path = output_path(&state.output_dir, output)?;
+    let parent = path.parent().unwrap_or(&state.output_dir);
+    tokio::fs::create_dir_all(parent).await.map_err(io_error)?;
+    // Symbolic links inside OUTPUT_DIR must not lead the upload out of it.
+    let output_dir = tokio::fs::canonicalize(&state.output_dir)
+        .await
+        .map_err(io_error)?;
+    let parent = tokio::fs::canonicalize(parent).await.map_err(io_error)?;
+    let is_link = tokio::fs::symlink_metadata(&path)
+        .await
+        .is_ok_and(|meta| meta.file_type().is_symlink());
+    if !parent.starts_with(&output_dir) || is_link {
+        let message = format!("Output {output} is outside of the output directory");
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+    let mut file = tokio::fs::File::create(&path).await.map_err(io_error)?;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = futures::StreamExt::next(&mut stream).await {
+        let chunk = chunk.map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, &chunk)
+            .await
+            .map_err(io_error)?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn report_status(
+    state: State<AppState>,
+    agent: Option<Extension<AgentIdentity>>,
+    Path(id): Path<i32>,
+    Query(query): Query<WorkerQuery>,
+    Json(report): Json<StatusReport>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    use task::TaskStatus::*;
+    check_agent(agent, &query.name)?;
+    if !matches!(report.status, Running | Success | Failed | Interrupted) {
+        let message = format!("Invalid status: {:?}", report.status);
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+    worker_task(&state, id, &query.name).await?;
+    if report.status == Running {
+        state.workers.confirm(&query.name, id);
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    update_task(&state, id, report.status)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!("Task {} finished on worker {}: {:?}", id, query.name, report.status);
+    state.workers.finish(&query.name, id);
+    state.runner.wake();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn terminated(mut terminate: watch::Receiver<bool>) {
+    let _ = terminate.wait_for(|terminate| *terminate).await;
+}
+
+const REDACTED: &[u8] = b"******";
+
+/// Shortest line of a multi-line secret which is redacted on its own.
+const MIN_SECRET_LINE: usize = 4;
+
+/// Texts to redact from the output, which is redacted line by line. Secrets spanning
+/// several lines, like keys, are redacted line by line too, except for very short lines
+/// which would hide punctuation everywhere.
+fn redact_patterns(secrets: &[String]) -> Vec<String> {
+    secrets
+        .iter()
+        .flat_map(|secret| {
+            if secret.contains('\n') {
+                secret
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| line.len() >= MIN_SECRET_LINE)
+                    .map(str::to_string)
+                    .collect()
+            } else {
+                vec![secret.clone()]
+            }
+        })
+        .filter(|pattern| !pattern.is_empty())
+        .collect()
+}
+
+/// Replaces every occurrence of the secrets in a line of output.
+fn redact(line: &[u8], secrets: &[String]) -> Vec<u8> {
+    let mut line = line.to_vec();
+    for secret in secrets {
+        let pattern = secret.as_bytes();
+        let mut redacted = Vec::with_capacity(line.len());
+        let mut rest = line.as_slice();
+        while !rest.is_empty() {
+            if rest.starts_with(pattern) {
+                redacted.extend_from_slice(REDACTED);
+                rest = &rest[pattern.len()..];
+            } else {
+                redacted.push(rest[0]);
+                rest = &rest[1..];
+            }
+        }
+        line = redacted;
+    }
+    line
+}
+
+/// Copies the output of a child process into the log file line by line.
+fn copy_output<R>(
+    reader: Option<R>,
+    log: Arc<tokio::sync::Mutex<tokio::fs::File>>,
+    secrets: Vec<String>,
+) -> JoinHandle<()>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let Some(reader) = reader else { return };
+        let mut reader = tokio::io::BufReader::new(reader);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line).await {
+                Ok(0) => break,
+                Ok(_) => {
+                    let line = redact(&line, &secrets);
+                    if let Err(err) = log.lock().await.write_all(&line).await {
+                        error!("Failed to write log file: {}", err);
+                        break;
+                    }
+                }
+                Err(err) => {
+                    error!("Failed to read task output: {}", err);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+pub async fn run_invocation(
+    invocation: &Invocation,
+    process: &ProcessSettings,
     work_dir: &std::path::Path,
     log_file: &std::path::Path,
     output_file: Option<&std::path::PathBuf>,
+    terminate: watch::Receiver<bool>,
 ) -> std::io::Result<()> {
-    let cmd = command.to_string();
-    let wd = work_dir.to_path_buf();
-    let log = log_file.to_path_buf();
-    let out = output_file.cloned();
-
-    tokio::task::spawn_blocking(move || {
-        let items = cmd.split(' ').collect::<Vec<_>>();
-        let mut file = std::fs::File::create(&log)?;
-        let io = Stdio::from(file.try_clone()?);
-        let io2 = Stdio::from(file.try_clone()?);
-        let mut just = Command::new("just")
-            .current_dir(&wd)
-            .args(items)
-            .stdout(io)
-            .stderr(io2)
-            .spawn()?;
-        let status = just.wait()?;
-
-        if status.success() {
-            if let Some(output_file) = out {
-                if output_file.is_file() {
-                    Ok(())
-                } else {
-                    let message = format!(
-                        "Command finished, but output file {} does not exist",
-                        output_file.display()
-                    );
-                    file.write_all(message.as_bytes())?;
-                    Err(std::io::Error::new(std::io::ErrorKind::Other, message))
-                }
-            } else {
-                Ok(())
-            }
-        } else {
-            let message = match status.code() {
-                Some(code) => format!("Command failed, return code: {code}"),
-                None => "Command terminated by signal".to_owned(),
-            };
-            file.write_all(message.as_bytes())?;
-            Err(std::io::Error::new(std::io::ErrorKind::Other, message))
+    let file = Arc::new(tokio::sync::Mutex::new(tokio::fs::File::create(log_file).await?));
+    let mut command = tokio::process::Command::new(&invocation.program);
+    process.apply(&mut command);
+    let mut child = command
+        .current_dir(work_dir)
+        .args(&invocation.args)
+        .env_remove("APP_SECRET")
+        .envs(invocation.env.iter().cloned())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let pid = child.id();
+    let secrets = &redact_patterns(&invocation.secrets);
+    let stdout = copy_output(child.stdout.take(), file.clone(), secrets.clone());
+    let stderr = copy_output(child.stderr.take(), file.clone(), secrets.clone());
+    let status = tokio::select! {
+        status = child.wait() => status?,
+        _ = terminated(terminate) => {
+            process::kill_group(pid);
+            child.kill().await?;
+            let message = "Task interrupted by server shutdown";
+            let mut file = file.lock().await;
+            file.write_all(message.as_bytes()).await?;
+            file.flush().await?;
+            return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, message));
         }
-    })
-    .await
-    .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "spawn_blocking failed"))?
+    };
+    // Clean up processes left behind by the task, they may also keep the pipes open.
+    process::kill_group(pid);
+    let _ = tokio::time::timeout(OUTPUT_DRAIN_TIMEOUT, futures::future::join(stdout, stderr)).await;
+    let mut file = file.lock().await;
+
+    let message = if !status.success() {
+        match status.code() {
+            Some(code) => format!("Command failed, return code: {code}"),
+            None => "Command terminated by signal".to_owned(),
+        }
+    } else if let Some(output_file) = output_file
+        && !tokio::fs::metadata(output_file)
+            .await
+            .is_ok_and(|meta| meta.is_file())
+    {
+        format!(
+            "Command finished, but output file {} does not exist",
+            output_file.display()
+        )
+    } else {
+        return file.flush().await;
+    };
+    file.write_all(message.as_bytes()).await?;
+    file.flush().await?;
+    Err(std::io::Error::other(message))
 }
 
+/// Authenticated user name, added to request extensions by `validate_jwt`.
+#[derive(Clone, Debug)]
+pub struct AuthUser(pub String);
+
+/// Name of the agent authenticated by an agent token, added to request extensions by
+/// `validate_agent`.
+#[derive(Clone, Debug)]
+pub struct AgentIdentity(pub String);
+
+/// Role of the tokens of `remote-task generate-agent-token`, which only work on `/agent`.
+pub const AGENT_ROLE: &str = "agent";
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct JwtPayload {
     pub user: String,
@@ -371,34 +2017,153 @@ pub struct JwtPayload {
 
 pub async fn validate_jwt(
     secret: State<String>,
-    request: Request,
+    mut request: Request,
     next: Next,
 ) -> impl IntoResponse {
+    match authenticated_user(&secret, &request) {
+        Some(user) => {
+            tracing::Span::current().record("user", user.as_str());
+            request.extensions_mut().insert(AuthUser(user));
+            next.run(request).await
+        }
+        None => (StatusCode::UNAUTHORIZED, "Invalid token".to_string()).into_response(),
+    }
+}
+
+/// Adds the user to requests of routes which also answer without a token, like
+/// `/readyz`. Every caller counts as a user when authentication is disabled.
+pub async fn identify_user(
+    secret: State<Option<String>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let user = match secret.as_deref() {
+        Some(secret) => authenticated_user(secret, &request),
+        None => Some("anonymous".to_string()),
+    };
+    if let Some(user) = user {
+        request.extensions_mut().insert(AuthUser(user));
+    }
+    next.run(request).await
+}
+
+/// User of a request, from its client certificate or its token. Agent tokens are not users.
+fn authenticated_user(secret: &str, request: &Request) -> Option<String> {
+    // A client certificate verified during the TLS handshake is as good as a token.
+    if let Some(ClientIdentity(Some(name))) = request.extensions().get::<ClientIdentity>() {
+        return Some(name.clone());
+    }
+    decode_token(secret, request)
+        .filter(|payload| payload.role != AGENT_ROLE)
+        .map(|payload| payload.user)
+}
+
+fn decode_token(secret: &str, request: &Request) -> Option<JwtPayload> {
     let jar = CookieJar::from_headers(request.headers());
-    if let Some(token) = jar.get("token").map(|c| c.value()) {
-        match jsonwebtoken::decode::<JwtPayload>(
-            token,
-            &jsonwebtoken::DecodingKey::from_secret(secret.as_bytes()),
-            &jsonwebtoken::Validation::default(),
-        ) {
-            Ok(_payload) => {
-                // info!("JWT payload: {:?}", payload.claims);
-                return next.run(request).await;
-            }
-            Err(err) => {
-                error!("JWT validation failed: {}", err);
-            }
+    let token = jar.get("token")?.value().to_string();
+    jsonwebtoken::decode::<JwtPayload>(
+        &token,
+        &jsonwebtoken::DecodingKey::from_secret(secret.as_bytes()),
+        &jsonwebtoken::Validation::default(),
+    )
+    .map(|payload| payload.claims)
+    .inspect_err(|err| error!("JWT validation failed: {}", err))
+    .ok()
+}
+
+/// Lets only agent tokens use the `/agent` routes, user tokens cannot act as workers.
+pub async fn validate_agent(
+    secret: State<String>,
+    mut request: Request,
+    next: Next,
+) -> impl IntoResponse {
+    match decode_token(&secret, &request) {
+        Some(payload) if payload.role == AGENT_ROLE => {
+            tracing::Span::current().record("user", payload.user.as_str());
+            request.extensions_mut().insert(AgentIdentity(payload.user));
+            next.run(request).await
         }
+        _ => (StatusCode::UNAUTHORIZED, "Invalid agent token".to_string()).into_response(),
+    }
+}
+
+/// Checks that an agent acts under the name of its token. Without authentication
+/// the name is taken as given.
+fn check_agent(
+    agent: Option<Extension<AgentIdentity>>,
+    name: &str,
+) -> Result<(), (StatusCode, String)> {
+    match agent {
+        Some(Extension(AgentIdentity(agent))) if agent != name => Err((
+            StatusCode::FORBIDDEN,
+            format!("The token of agent {agent} cannot act as worker {name}"),
+        )),
+        _ => Ok(()),
     }
-    (StatusCode::UNAUTHORIZED, "Invalid token".to_string()).into_response()
 }
 
-pub fn generate_token(user: String, days: i64) {
+/// Returns `APP_SECRET`, falling back to the secret stored in `SECRET_FILE`.
+/// A random secret is generated and stored there when neither is set.
+pub fn load_or_create_secret() -> std::io::Result<String> {
+    if let Ok(secret) = std::env::var("APP_SECRET")
+        && !secret.is_empty()
+    {
+        return Ok(secret);
+    }
+    let path = PathBuf::from(std::env::var("SECRET_FILE").unwrap_or("secret.key".to_string()));
+    if path.is_file() {
+        let secret = std::fs::read_to_string(&path)?.trim().to_string();
+        if !secret.is_empty() {
+            return Ok(secret);
+        }
+    }
+    let secret = rand::random::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&path)?.write_all(secret.as_bytes())?;
+    warn!(
+        "APP_SECRET is empty, generated a random secret and stored it in {}",
+        path.display()
+    );
+    Ok(secret)
+}
+
+fn is_loopback(host: &str) -> bool {
+    host == "localhost"
+        || host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Warns about configurations that expose task execution to the network.
+pub fn security_self_check(host: &str, secret: Option<&str>, tls: bool) {
+    let public = !is_loopback(host);
+    match secret {
+        None if public => warn!(
+            "Authentication is disabled and the server listens on {host}, anyone who can reach it can run tasks"
+        ),
+        None => warn!("Authentication is disabled (--insecure-no-auth)"),
+        Some(secret) if secret.len() < 32 => {
+            warn!("APP_SECRET is shorter than 32 characters, tokens may be brute forced")
+        }
+        Some(_) => {}
+    }
+    if public && secret.is_some() && !tls {
+        warn!("The server listens on {host} over plain HTTP, tokens are sent unencrypted");
+    }
+}
+
+pub fn generate_token(user: String, role: &str, days: i64) {
     dotenvy::dotenv().ok();
-    let secret = match std::env::var("APP_SECRET") {
-        Ok(s) if !s.is_empty() => s,
-        _ => {
-            eprintln!("APP_SECRET not set");
+    let secret = match load_or_create_secret() {
+        Ok(secret) => secret,
+        Err(err) => {
+            eprintln!("Failed to load APP_SECRET: {}", err);
             std::process::exit(1);
         }
     };
@@ -408,7 +2173,7 @@ pub fn generate_token(user: String, days: i64) {
         .as_secs() as i64;
     let payload = JwtPayload {
         user,
-        role: "admin".to_string(),
+        role: role.to_string(),
         iat: now,
         exp: now + 60 * 60 * 24 * days,
     };
@@ -424,3 +2189,54 @@ pub fn generate_token(user: String, days: i64) {
     }
     println!("Token generated and saved to token.txt.");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "remote-task-service-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn package_urls_are_encoded() {
+        assert_eq!(package_url("app.zip"), "/package/app.zip");
+        assert_eq!(
+            package_url("release 1/app#2?%.zip"),
+            "/package/release%201/app%232%3F%25.zip"
+        );
+        assert_eq!(package_url("./builds/été.tar"), "/package/builds/%C3%A9t%C3%A9.tar");
+    }
+
+    #[tokio::test]
+    async fn secrets_cannot_replace_protected_variables() {
+        let state = AppState::for_test(&test_dir("secrets")).await;
+        let request = |value: &str| {
+            Json(SecretRequest {
+                value: value.to_string(),
+                recipes: Vec::new(),
+            })
+        };
+        for name in ["PATH", "LD_PRELOAD", "APP_SECRET", "bash_env"] {
+            let result =
+                save_secret(State(state.clone()), Path(name.to_string()), request("x")).await;
+            assert_eq!(result.unwrap_err().0, StatusCode::BAD_REQUEST, "{name}");
+        }
+        let result =
+            save_secret(State(state.clone()), Path("DEPLOY_TOKEN".to_string()), request("x")).await;
+        assert_eq!(result.unwrap(), StatusCode::NO_CONTENT);
+        let names: Vec<String> = secret::list_secrets(&state.conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|secret| secret.name)
+            .collect();
+        assert_eq!(names, ["DEPLOY_TOKEN"]);
+    }
+}
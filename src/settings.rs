@@ -0,0 +1,120 @@
+use crate::container::ContainerSettings;
+use crate::process::ProcessSettings;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the optional settings file inside each work directory.
+pub const SETTINGS_FILE: &str = "remote-task.json";
+
+/// Settings of one work directory, read from `remote-task.json`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DirSettings {
+    /// Tool which runs the tasks of the directory.
+    pub executor: ExecutorSettings,
+    /// File of environment variables for all tasks, relative to the work directory.
+    pub env_file: Option<PathBuf>,
+    /// Resource limits and environment of the task processes.
+    pub process: ProcessSettings,
+    /// Runs the tasks inside a container when present.
+    pub container: Option<ContainerSettings>,
+    /// Labels of the directory, like `["os=linux", "gpu"]`, matched against task requirements.
+    pub labels: Vec<String>,
+    /// Settings of individual recipes, keyed by recipe name.
+    pub recipes: HashMap<String, RecipeSettings>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ExecutorSettings {
+    pub kind: ExecutorKind,
+    /// Path of the `just` or `make` binary, found on `PATH` by default.
+    pub binary: Option<PathBuf>,
+    /// Extra flags passed before the target, like `["--set", "key", "value"]`.
+    pub args: Vec<String>,
+    /// Justfile or makefile to use instead of the one found in the work directory.
+    pub file: Option<PathBuf>,
+    /// Directory of the `script` executor, relative to the work directory, defaults to `scripts`.
+    pub scripts: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutorKind {
+    #[default]
+    Just,
+    Make,
+    Script,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RecipeSettings {
+    pub dedup: Option<DedupPolicy>,
+    /// Concurrency key of the recipe, defaults to the work directory.
+    pub concurrency: Option<String>,
+}
+
+/// What to do when an identical task is already pending or running.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupPolicy {
+    /// Return the existing task instead of queuing a new one.
+    Return,
+    /// Reject the submission with `409 Conflict`.
+    Reject,
+    /// Queue the task anyway.
+    #[default]
+    Queue,
+}
+
+impl std::str::FromStr for DedupPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "return" => Ok(DedupPolicy::Return),
+            "reject" => Ok(DedupPolicy::Reject),
+            "queue" => Ok(DedupPolicy::Queue),
+            _ => Err(format!("unknown dedup policy: {s}")),
+        }
+    }
+}
+
+impl DirSettings {
+    /// Loads the settings of a work directory, a missing file gives the defaults.
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(SETTINGS_FILE);
+        if !path.is_file() {
+            return Ok(DirSettings::default());
+        }
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let settings: DirSettings = serde_json::from_str(&content)
+            .with_context(|| format!("invalid {}", path.display()))?;
+        settings
+            .validate()
+            .with_context(|| format!("invalid {}", path.display()))?;
+        Ok(settings)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        self.process.validate()?;
+        if let Some(container) = &self.container {
+            if container.image.trim().is_empty() {
+                anyhow::bail!("container.image must not be empty");
+            }
+            // Tasks run either in a container or in a sandbox, never in both.
+            if self.process.sandbox.is_some() {
+                anyhow::bail!("container and process.sandbox cannot be used together");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn recipe(&self, name: &str) -> RecipeSettings {
+        self.recipes.get(name).cloned().unwrap_or_default()
+    }
+}
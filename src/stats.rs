@@ -0,0 +1,141 @@
+use crate::task::{FinishedRun, Model, TaskStatus};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Run counts and durations of the tasks of one recipe in one directory.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RecipeStats {
+    pub dir: String,
+    pub recipe: String,
+    pub runs: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub interrupted: u64,
+    /// Successes out of successes and failures, interrupted runs are left out.
+    pub success_rate: Option<f64>,
+    /// Median run time of the successful runs, in seconds.
+    pub p50_secs: Option<f64>,
+    pub p95_secs: Option<f64>,
+    pub last_success: Option<LastRun>,
+    pub last_failure: Option<LastRun>,
+    pub daily: Vec<DailyStats>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LastRun {
+    pub id: i32,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub finished_at: Option<time::OffsetDateTime>,
+}
+
+impl From<&Model> for LastRun {
+    fn from(task: &Model) -> Self {
+        LastRun {
+            id: task.id,
+            finished_at: task.finished_at,
+        }
+    }
+}
+
+/// Runs of one day, only days with runs are listed.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DailyStats {
+    pub date: String,
+    pub runs: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub p50_secs: Option<f64>,
+}
+
+/// Nearest rank percentile of sorted values.
+fn percentile(sorted: &[f64], percent: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+#[derive(Default)]
+struct Counts {
+    runs: u64,
+    successes: u64,
+    failures: u64,
+    interrupted: u64,
+    durations: Vec<f64>,
+}
+
+impl Counts {
+    fn add(&mut self, run: &FinishedRun) {
+        self.runs += 1;
+        match run.status {
+            TaskStatus::Success => {
+                self.successes += 1;
+                self.durations.extend(run.duration);
+            }
+            TaskStatus::Failed => self.failures += 1,
+            _ => self.interrupted += 1,
+        }
+    }
+
+    fn percentile(&mut self, percent: f64) -> Option<f64> {
+        self.durations.sort_by(f64::total_cmp);
+        percentile(&self.durations, percent)
+    }
+}
+
+/// Counts of a recipe over the whole period and by date.
+type Group<'a> = (Counts, BTreeMap<&'a str, Counts>);
+
+/// Groups finished runs by directory and recipe, most run recipes first.
+pub fn aggregate(
+    runs: &[FinishedRun],
+    last: HashMap<(String, String, TaskStatus), &Model>,
+) -> Vec<RecipeStats> {
+    let mut groups: HashMap<(&str, &str), Group> = HashMap::new();
+    for run in runs {
+        let (total, daily) = groups.entry((&run.dir, &run.recipe)).or_default();
+        total.add(run);
+        daily.entry(&run.date).or_default().add(run);
+    }
+    let mut stats = groups
+        .into_iter()
+        .map(|((dir, recipe), (mut total, daily))| {
+            let last_run = |status| {
+                last.get(&(dir.to_string(), recipe.to_string(), status))
+                    .map(|&task| LastRun::from(task))
+            };
+            let decided = total.successes + total.failures;
+            RecipeStats {
+                dir: dir.to_string(),
+                recipe: recipe.to_string(),
+                success_rate: (decided > 0).then(|| total.successes as f64 / decided as f64),
+                p50_secs: total.percentile(50.0),
+                p95_secs: total.percentile(95.0),
+                last_success: last_run(TaskStatus::Success),
+                last_failure: last_run(TaskStatus::Failed),
+                daily: daily
+                    .into_iter()
+                    .map(|(date, mut counts)| DailyStats {
+                        date: date.to_string(),
+                        p50_secs: counts.percentile(50.0),
+                        runs: counts.runs,
+                        successes: counts.successes,
+                        failures: counts.failures,
+                    })
+                    .collect(),
+                runs: total.runs,
+                successes: total.successes,
+                failures: total.failures,
+                interrupted: total.interrupted,
+            }
+        })
+        .collect::<Vec<_>>();
+    stats.sort_by(|a, b| {
+        b.runs
+            .cmp(&a.runs)
+            .then_with(|| a.dir.cmp(&b.dir))
+            .then_with(|| a.recipe.cmp(&b.recipe))
+    });
+    stats
+}
@@ -1,7 +1,11 @@
-use sea_orm::{DbConn, QueryOrder, Set, TryIntoModel, Unchanged, entity::prelude::*};
-use serde::Serialize;
+use sea_orm::{
+    Condition, DbConn, QueryOrder, QuerySelect, Set, TransactionTrait, TryIntoModel, Unchanged,
+    entity::prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Clone, Debug, DeriveEntityModel, Serialize)]
+#[derive(Clone, Debug, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "task")]
 pub struct Model {
     #[sea_orm(primary_key)]
@@ -11,13 +15,42 @@ pub struct Model {
     pub command: String,
     pub output: Option<String>,
     pub status: TaskStatus,
+    pub submitter: String,
+    pub idempotency_key: String,
+    /// Tasks with the same key never run at the same time.
+    pub concurrency_key: String,
+    /// Why a pending task has not started yet.
+    pub waiting_reason: String,
+    /// Environment variables of the task run.
+    pub env: Json,
+    /// Agent which runs the task, empty when it runs on the server.
+    pub worker: String,
+    /// Labels the machine running the task must have, like `os=windows`.
+    pub labels: Json,
     #[serde(with = "time::serde::rfc3339")]
     pub created_at: time::OffsetDateTime,
     #[serde(with = "time::serde::rfc3339")]
     pub updated_at: time::OffsetDateTime,
+    /// When the last run started, cleared when the task is reset.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub started_at: Option<time::OffsetDateTime>,
+    /// When the last run ended.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub finished_at: Option<time::OffsetDateTime>,
+    /// OpenTelemetry trace of the request which submitted the task, empty when tracing is off.
+    pub trace_id: String,
+    /// Span of the request which submitted the task.
+    pub span_id: String,
+    /// W3C trace flags of the request which submitted the task, whether it was sampled.
+    pub trace_flags: i32,
+    /// Number of the last change of the task, increasing in commit order across all tasks.
+    /// Set by the database triggers of `create_table_if_not_exists`.
+    #[sea_orm(default_value = 0)]
+    #[serde(skip)]
+    pub seq: i64,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, EnumIter, DeriveActiveEnum, Serialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
 #[sea_orm(rs_type = "String", db_type = "String(StringLen::N(1))")]
 pub enum TaskStatus {
     #[sea_orm(string_value = "P")]
@@ -28,6 +61,8 @@ pub enum TaskStatus {
     Success,
     #[sea_orm(string_value = "F")]
     Failed,
+    #[sea_orm(string_value = "I")]
+    Interrupted,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -35,12 +70,96 @@ pub enum Relation {}
 
 impl ActiveModelBehavior for ActiveModel {}
 
+impl TaskStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            TaskStatus::Success | TaskStatus::Failed | TaskStatus::Interrupted
+        )
+    }
+}
+
+impl std::str::FromStr for TaskStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pending" => Ok(TaskStatus::Pending),
+            "running" => Ok(TaskStatus::Running),
+            "success" => Ok(TaskStatus::Success),
+            "failed" => Ok(TaskStatus::Failed),
+            "interrupted" => Ok(TaskStatus::Interrupted),
+            _ => Err(format!("unknown task status: {s}")),
+        }
+    }
+}
+
 impl Model {
+    pub fn env(&self) -> HashMap<String, String> {
+        serde_json::from_value(self.env.clone()).unwrap_or_default()
+    }
+
+    pub fn labels(&self) -> Vec<String> {
+        serde_json::from_value(self.labels.clone()).unwrap_or_default()
+    }
+
+    pub fn concurrency_key(&self) -> &str {
+        if self.concurrency_key.is_empty() {
+            &self.dir
+        } else {
+            &self.concurrency_key
+        }
+    }
+
+    /// Run time of a finished task, or so far of a running one.
+    pub fn duration(&self) -> Option<time::Duration> {
+        let started_at = self.started_at?;
+        let end = match self.status {
+            TaskStatus::Running => TimeDateTimeWithTimeZone::now_utc(),
+            _ => self.finished_at?,
+        };
+        Some(end - started_at)
+    }
+
     pub fn month(&self) -> String {
         let year = self.created_at.year();
         let month = self.created_at.month() as u8;
         format!("{year}-{month:02}")
     }
+
+    /// Task `build` in the directory `work`, running since now.
+    #[cfg(test)]
+    pub fn for_test(id: i32) -> Self {
+        let now = TimeDateTimeWithTimeZone::now_utc();
+        Model {
+            id,
+            name: "build".to_string(),
+            dir: "work".to_string(),
+            command: "build".to_string(),
+            output: None,
+            status: TaskStatus::Running,
+            submitter: "alice".to_string(),
+            idempotency_key: String::new(),
+            concurrency_key: String::new(),
+            waiting_reason: String::new(),
+            env: serde_json::json!({}),
+            worker: String::new(),
+            labels: serde_json::json!([]),
+            created_at: now,
+            updated_at: now,
+            started_at: Some(now),
+            finished_at: None,
+            trace_id: String::new(),
+            span_id: String::new(),
+            trace_flags: 0,
+            seq: 0,
+        }
+    }
+}
+
+/// Recipe name of a command, which is its first word.
+pub fn recipe_of(command: &str) -> &str {
+    command.split_ascii_whitespace().next().unwrap_or("")
 }
 
 pub async fn add_column_if_missing(db: &DbConn, table_name: &str, column_name: &str, column_type: &str, column_default: &str) -> Result<(), DbErr> {
@@ -54,16 +173,22 @@ pub async fn add_column_if_missing(db: &DbConn, table_name: &str, column_name: &
             );
             let result = db.query_all(sql).await?;
 
-            result.iter().find(|r| {
+            result.iter().any(|r| {
                 r.try_get::<String>("", "name") == Ok(column_name.to_string())
-            }).is_some()
+            })
         }
         _ => unreachable!(),
     };
     if !column_exists {
+        // A NULL default makes the column nullable.
+        let definition = if column_default == "NULL" {
+            column_type.to_string()
+        } else {
+            format!("{column_type} NOT NULL DEFAULT {column_default}")
+        };
         let sql = sea_orm::Statement::from_sql_and_values(
             backend,
-            format!("ALTER TABLE {table_name} ADD COLUMN {column_name} {column_type} NOT NULL DEFAULT {column_default}"),
+            format!("ALTER TABLE {table_name} ADD COLUMN {column_name} {definition}"),
             vec![],
         );
         db.execute(sql).await?;
@@ -80,26 +205,99 @@ pub async fn create_table_if_not_exists(db: &DbConn) -> Result<(), DbErr> {
 
     // Check for necessary migrations.
     add_column_if_missing(db, "task", "dir", "TEXT", "''").await?;
+    add_column_if_missing(db, "task", "submitter", "TEXT", "''").await?;
+    add_column_if_missing(db, "task", "idempotency_key", "TEXT", "''").await?;
+    add_column_if_missing(db, "task", "concurrency_key", "TEXT", "''").await?;
+    add_column_if_missing(db, "task", "waiting_reason", "TEXT", "''").await?;
+    add_column_if_missing(db, "task", "env", "TEXT", "'{}'").await?;
+    add_column_if_missing(db, "task", "worker", "TEXT", "''").await?;
+    add_column_if_missing(db, "task", "labels", "TEXT", "'[]'").await?;
+    add_column_if_missing(db, "task", "started_at", "TEXT", "NULL").await?;
+    add_column_if_missing(db, "task", "finished_at", "TEXT", "NULL").await?;
+    add_column_if_missing(db, "task", "trace_id", "TEXT", "''").await?;
+    add_column_if_missing(db, "task", "span_id", "TEXT", "''").await?;
+    // Traces were only stored when sampled before the flags were.
+    add_column_if_missing(db, "task", "trace_flags", "INTEGER", "1").await?;
+    add_column_if_missing(db, "task", "seq", "INTEGER", "0").await?;
+
+    // Full-text index of the logs of finished tasks, the rowid is the task id.
+    let sql = "CREATE VIRTUAL TABLE IF NOT EXISTS task_log USING fts5(content)";
+    db.execute_unprepared(sql).await?;
+    // Statistics read the finished tasks of recent days.
+    let sql = "CREATE INDEX IF NOT EXISTS task_finished_at ON task (finished_at)";
+    db.execute_unprepared(sql).await?;
+
+    // Every change takes the next sequence number inside the writing transaction, and
+    // SQLite has one writer at a time, so the numbers follow the commit order which
+    // `since` syncs rely on. Deleted tasks leave a tombstone with their last number.
+    let sql = "CREATE TABLE IF NOT EXISTS task_tombstone \
+        (id INTEGER NOT NULL PRIMARY KEY, seq INTEGER NOT NULL)";
+    db.execute_unprepared(sql).await?;
+    let sql = "CREATE INDEX IF NOT EXISTS task_tombstone_seq ON task_tombstone (seq)";
+    db.execute_unprepared(sql).await?;
+    let sql = "CREATE INDEX IF NOT EXISTS task_seq ON task (seq)";
+    db.execute_unprepared(sql).await?;
+    // Tasks from before the sequence keep the order they were created in.
+    db.execute_unprepared("UPDATE task SET seq = id WHERE seq = 0")
+        .await?;
+    let next = "COALESCE((SELECT MAX(seq) FROM (SELECT MAX(seq) AS seq FROM task \
+        UNION ALL SELECT MAX(seq) FROM task_tombstone)), 0) + 1";
+    let sql = format!(
+        "CREATE TRIGGER IF NOT EXISTS task_seq_insert AFTER INSERT ON task BEGIN \
+         UPDATE task SET seq = {next} WHERE id = NEW.id; END"
+    );
+    db.execute_unprepared(&sql).await?;
+    let sql = format!(
+        "CREATE TRIGGER IF NOT EXISTS task_seq_update AFTER UPDATE ON task \
+         WHEN NEW.seq = OLD.seq BEGIN UPDATE task SET seq = {next} WHERE id = NEW.id; END"
+    );
+    db.execute_unprepared(&sql).await?;
+    let sql = format!(
+        "CREATE TRIGGER IF NOT EXISTS task_seq_delete BEFORE DELETE ON task BEGIN \
+         INSERT OR REPLACE INTO task_tombstone (id, seq) VALUES (OLD.id, {next}); END"
+    );
+    db.execute_unprepared(&sql).await?;
 
     Ok(())
 }
 
-pub async fn create_task(
-    db: &DbConn,
-    dir: String,
-    name: String,
-    command: String,
-    output: Option<String>,
-) -> Result<Model, DbErr> {
+pub struct NewTask {
+    pub dir: String,
+    pub name: String,
+    pub command: String,
+    pub output: Option<String>,
+    pub submitter: String,
+    pub idempotency_key: String,
+    pub concurrency_key: String,
+    pub env: HashMap<String, String>,
+    pub labels: Vec<String>,
+    pub trace_id: String,
+    pub span_id: String,
+    pub trace_flags: i32,
+}
+
+pub async fn create_task(db: &DbConn, task: NewTask) -> Result<Model, DbErr> {
     let now = TimeDateTimeWithTimeZone::now_utc();
     ActiveModel {
-        name: Set(name),
-        dir: Set(dir),
-        command: Set(command),
-        output: Set(output),
+        name: Set(task.name),
+        dir: Set(task.dir),
+        command: Set(task.command),
+        output: Set(task.output),
         status: Set(TaskStatus::Pending),
+        submitter: Set(task.submitter),
+        idempotency_key: Set(task.idempotency_key),
+        concurrency_key: Set(task.concurrency_key),
+        waiting_reason: Set(String::new()),
+        env: Set(serde_json::json!(task.env)),
+        worker: Set(String::new()),
+        labels: Set(serde_json::json!(task.labels)),
         created_at: Set(now),
         updated_at: Set(now),
+        started_at: Set(None),
+        finished_at: Set(None),
+        trace_id: Set(task.trace_id),
+        span_id: Set(task.span_id),
+        trace_flags: Set(task.trace_flags),
         ..Default::default()
     }
     .save(db)
@@ -113,9 +311,72 @@ pub async fn update_task(db: &DbConn, id: i32, status: TaskStatus) -> Result<Mod
         .await?
         .ok_or(DbErr::Custom("Cannot find task.".to_owned()))?;
 
-    ActiveModel {
+    let now = TimeDateTimeWithTimeZone::now_utc();
+    let mut task = ActiveModel {
         id: Unchanged(task.id),
         status: Set(status),
+        waiting_reason: Set(String::new()),
+        updated_at: Set(now),
+        ..Default::default()
+    };
+    if status.is_finished() {
+        task.finished_at = Set(Some(now));
+    } else if status == TaskStatus::Pending {
+        task.started_at = Set(None);
+        task.finished_at = Set(None);
+    }
+    task.update(db).await
+}
+
+/// Marks a task running on the worker, empty for the server itself.
+pub async fn start_task(db: &DbConn, id: i32, worker: &str) -> Result<Model, DbErr> {
+    let now = TimeDateTimeWithTimeZone::now_utc();
+    ActiveModel {
+        id: Unchanged(id),
+        status: Set(TaskStatus::Running),
+        worker: Set(worker.to_string()),
+        waiting_reason: Set(String::new()),
+        updated_at: Set(now),
+        started_at: Set(Some(now)),
+        finished_at: Set(None),
+        ..Default::default()
+    }
+    .update(db)
+    .await
+}
+
+pub async fn get_task(db: &DbConn, id: i32) -> Result<Option<Model>, DbErr> {
+    Entity::find_by_id(id).one(db).await
+}
+
+/// Finds the last finished run of the same command in the same directory before a task.
+pub async fn previous_run(db: &DbConn, task: &Model) -> Result<Option<Model>, DbErr> {
+    Entity::find()
+        .filter(Column::Id.lt(task.id))
+        .filter(Column::Dir.eq(&task.dir))
+        .filter(Column::Command.eq(&task.command))
+        .filter(Column::Status.is_in([
+            TaskStatus::Success,
+            TaskStatus::Failed,
+            TaskStatus::Interrupted,
+        ]))
+        .order_by_desc(Column::Id)
+        .one(db)
+        .await
+}
+
+pub async fn running_tasks_on_worker(db: &DbConn, worker: &str) -> Result<Vec<Model>, DbErr> {
+    Entity::find()
+        .filter(Column::Status.eq(TaskStatus::Running))
+        .filter(Column::Worker.eq(worker))
+        .all(db)
+        .await
+}
+
+pub async fn set_waiting_reason(db: &DbConn, id: i32, reason: String) -> Result<Model, DbErr> {
+    ActiveModel {
+        id: Unchanged(id),
+        waiting_reason: Set(reason),
         updated_at: Set(TimeDateTimeWithTimeZone::now_utc()),
         ..Default::default()
     }
@@ -123,11 +384,41 @@ pub async fn update_task(db: &DbConn, id: i32, status: TaskStatus) -> Result<Mod
     .await
 }
 
-pub async fn delete_task(db: &DbConn, id: i32) -> Result<bool, DbErr> {
-    Entity::delete_by_id(id)
+/// Resets tasks left running by a previous process to the given status.
+pub async fn reset_running_tasks(db: &DbConn, status: TaskStatus) -> Result<u64, DbErr> {
+    let now = TimeDateTimeWithTimeZone::now_utc();
+    let mut update = Entity::update_many()
+        .col_expr(Column::Status, Expr::value(status))
+        .col_expr(Column::UpdatedAt, Expr::value(now));
+    update = if status.is_finished() {
+        update.col_expr(Column::FinishedAt, Expr::value(now))
+    } else {
+        let none = None::<TimeDateTimeWithTimeZone>;
+        update
+            .col_expr(Column::StartedAt, Expr::value(none))
+            .col_expr(Column::FinishedAt, Expr::value(none))
+    };
+    // Tasks of agents keep running, the agents report them when they register again.
+    update
+        .filter(Column::Status.eq(TaskStatus::Running))
+        .filter(Column::Worker.eq(""))
         .exec(db)
         .await
-        .map(|m| m.rows_affected == 1)
+        .map(|res| res.rows_affected)
+}
+
+/// Deletes a task and its indexed log.
+pub async fn delete_task(db: &DbConn, id: i32) -> Result<bool, DbErr> {
+    let txn = db.begin().await?;
+    txn.execute(sea_orm::Statement::from_sql_and_values(
+        txn.get_database_backend(),
+        "DELETE FROM task_log WHERE rowid = ?",
+        [id.into()],
+    ))
+    .await?;
+    let deleted = Entity::delete_by_id(id).exec(&txn).await?;
+    txn.commit().await?;
+    Ok(deleted.rows_affected == 1)
 }
 
 pub async fn pending_tasks(db: &DbConn) -> Result<Vec<Model>, DbErr> {
@@ -138,15 +429,542 @@ pub async fn pending_tasks(db: &DbConn) -> Result<Vec<Model>, DbErr> {
         .await
 }
 
-pub async fn recent_tasks(
+/// Number of tasks of every status, zero included.
+pub async fn count_by_status(db: &DbConn) -> Result<Vec<(TaskStatus, u64)>, DbErr> {
+    let counts = Entity::find()
+        .select_only()
+        .column(Column::Status)
+        .column_as(Column::Id.count(), "count")
+        .group_by(Column::Status)
+        .into_tuple::<(TaskStatus, i64)>()
+        .all(db)
+        .await?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    Ok(<TaskStatus as sea_orm::Iterable>::iter()
+        .map(|status| (status, counts.get(&status).copied().unwrap_or(0) as u64))
+        .collect())
+}
+
+pub async fn pending_count_by_submitter(db: &DbConn, submitter: &str) -> Result<u64, DbErr> {
+    Entity::find()
+        .filter(Column::Status.eq(TaskStatus::Pending))
+        .filter(Column::Submitter.eq(submitter))
+        .count(db)
+        .await
+}
+
+pub async fn pending_count_by_command(db: &DbConn, dir: &str, command: &str) -> Result<u64, DbErr> {
+    Entity::find()
+        .filter(Column::Status.eq(TaskStatus::Pending))
+        .filter(Column::Dir.eq(dir))
+        .filter(Column::Command.eq(command))
+        .count(db)
+        .await
+}
+
+/// Finds a pending or running task which runs the same command in the same directory
+/// with the same environment and label requirements.
+pub async fn find_active(
     db: &DbConn,
+    dir: &str,
+    command: &str,
+    env: &HashMap<String, String>,
+    labels: &[String],
+) -> Result<Option<Model>, DbErr> {
+    let tasks = Entity::find()
+        .filter(Column::Status.is_in([TaskStatus::Pending, TaskStatus::Running]))
+        .filter(Column::Dir.eq(dir))
+        .filter(Column::Command.eq(command))
+        .order_by_asc(Column::Id)
+        .all(db)
+        .await?;
+    Ok(tasks
+        .into_iter()
+        .find(|task| task.env() == *env && task.labels() == labels))
+}
+
+pub async fn find_by_idempotency_key(
+    db: &DbConn,
+    submitter: &str,
+    key: &str,
+) -> Result<Option<Model>, DbErr> {
+    Entity::find()
+        .filter(Column::Submitter.eq(submitter))
+        .filter(Column::IdempotencyKey.eq(key))
+        .one(db)
+        .await
+}
+
+/// Filters of the task list, empty fields match every task.
+#[derive(Clone, Debug, Default)]
+pub struct TaskFilter {
+    pub statuses: Vec<TaskStatus>,
+    /// Substring of the task name.
+    pub name: Option<String>,
+    /// Substring of the command.
+    pub command: Option<String>,
+    pub dir: Option<String>,
+    pub submitter: Option<String>,
+    /// Created at or after this time.
+    pub from: Option<TimeDateTimeWithTimeZone>,
+    /// Created before this time.
+    pub to: Option<TimeDateTimeWithTimeZone>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    Id,
+    CreatedAt,
+    UpdatedAt,
+    Name,
+    Status,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl TaskFilter {
+    fn query(&self) -> Select<Entity> {
+        let filter = self;
+        let mut query = Entity::find();
+        if !filter.statuses.is_empty() {
+            query = query.filter(Column::Status.is_in(filter.statuses.clone()));
+        }
+        if let Some(name) = &filter.name {
+            query = query.filter(Column::Name.contains(name));
+        }
+        if let Some(command) = &filter.command {
+            query = query.filter(Column::Command.contains(command));
+        }
+        if let Some(dir) = &filter.dir {
+            query = query.filter(Column::Dir.eq(dir));
+        }
+        if let Some(submitter) = &filter.submitter {
+            query = query.filter(Column::Submitter.eq(submitter));
+        }
+        if let Some(from) = filter.from {
+            query = query.filter(Column::CreatedAt.gte(from));
+        }
+        if let Some(to) = filter.to {
+            query = query.filter(Column::CreatedAt.lt(to));
+        }
+        query
+    }
+}
+
+/// Returns a page of the tasks matching the filter, the number of pages and of tasks.
+pub async fn list_tasks(
+    db: &DbConn,
+    filter: &TaskFilter,
+    sort: SortField,
+    order: SortOrder,
     page_size: u64,
     page: u64,
-) -> Result<(Vec<Model>, u64), DbErr> {
-    let paginator = Entity::find()
-        .order_by_desc(Column::Id)
+) -> Result<(Vec<Model>, u64, u64), DbErr> {
+    let query = filter.query();
+    let column = match sort {
+        SortField::Id => Expr::col(Column::Id).into(),
+        SortField::CreatedAt => time_key(Column::CreatedAt),
+        SortField::UpdatedAt => time_key(Column::UpdatedAt),
+        SortField::Name => Expr::col(Column::Name).into(),
+        SortField::Status => Expr::col(Column::Status).into(),
+    };
+    let order = match order {
+        SortOrder::Asc => sea_orm::Order::Asc,
+        SortOrder::Desc => sea_orm::Order::Desc,
+    };
+    // Sort by id as well so pages are stable when the field has equal values.
+    let paginator = query
+        .order_by(column, order.clone())
+        .order_by(Column::Id, order)
         .paginate(db, page_size);
-    let pages = paginator.num_pages().await?;
+    let counts = paginator.num_items_and_pages().await?;
     let items = paginator.fetch_page(page).await?;
-    Ok((items, pages))
+    Ok((items, counts.number_of_pages, counts.number_of_items))
+}
+
+/// Position after a task in a list sorted by id, or by update time then id.
+/// Written as `ID` or `UPDATED_AT_NANOS.ID`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cursor {
+    pub updated_at: Option<TimeDateTimeWithTimeZone>,
+    pub id: i32,
+}
+
+impl Cursor {
+    pub fn of(task: &Model, sort: SortField) -> Self {
+        Cursor {
+            updated_at: (sort == SortField::UpdatedAt).then_some(task.updated_at),
+            id: task.id,
+        }
+    }
+
+    pub fn sort(&self) -> SortField {
+        match self.updated_at {
+            Some(_) => SortField::UpdatedAt,
+            None => SortField::Id,
+        }
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.updated_at {
+            Some(updated_at) => write!(f, "{}.{}", updated_at.unix_timestamp_nanos(), self.id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid cursor: {s}");
+        let (updated_at, id) = match s.split_once('.') {
+            Some((nanos, id)) => {
+                let nanos = nanos.parse().map_err(|_| invalid())?;
+                let updated_at = TimeDateTimeWithTimeZone::from_unix_timestamp_nanos(nanos)
+                    .map_err(|_| invalid())?;
+                (Some(updated_at), id)
+            }
+            None => (None, s),
+        };
+        let id = id.parse().map_err(|_| invalid())?;
+        Ok(Cursor { updated_at, id })
+    }
+}
+
+/// Sortable text of a time column. Times are stored as RFC 3339 in UTC with the trailing
+/// zeros of the fraction removed, which does not sort as text, so it is padded to nanoseconds.
+fn time_key(column: Column) -> sea_orm::sea_query::SimpleExpr {
+    let name = column.as_str();
+    Expr::cust(format!(
+        "substr({name}, 1, 19) || '.' || substr(rtrim(substr({name}, 21), 'Z') || '000000000', 1, 9)"
+    ))
+}
+
+fn time_key_value(time: TimeDateTimeWithTimeZone) -> String {
+    let time = time.to_offset(time::UtcOffset::UTC);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}",
+        time.year(),
+        time.month() as u8,
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second(),
+        time.nanosecond()
+    )
+}
+
+/// Tasks matching the filter and deleted task ids with their sequence numbers, changed
+/// after the sequence number `seq`, up to `limit` of each, oldest change first.
+pub async fn changes_since(
+    db: &DbConn,
+    filter: &TaskFilter,
+    seq: i64,
+    limit: u64,
+) -> Result<(Vec<Model>, Vec<(i32, i64)>), DbErr> {
+    let tasks = filter
+        .query()
+        .filter(Column::Seq.gt(seq))
+        .order_by_asc(Column::Seq)
+        .limit(limit)
+        .all(db)
+        .await?;
+    let rows = db
+        .query_all(sea_orm::Statement::from_sql_and_values(
+            db.get_database_backend(),
+            "SELECT id, seq FROM task_tombstone WHERE seq > ? ORDER BY seq LIMIT ?",
+            [seq.into(), limit.into()],
+        ))
+        .await?;
+    let deleted = rows
+        .iter()
+        .map(|row| Ok((row.try_get("", "id")?, row.try_get("", "seq")?)))
+        .collect::<Result<_, DbErr>>()?;
+    Ok((tasks, deleted))
+}
+
+/// Returns up to `limit` tasks matching the filter which come after the cursor, sorted by
+/// id or by update time. Unlike `list_tasks` the pages do not shift when tasks are added.
+pub async fn list_tasks_after(
+    db: &DbConn,
+    filter: &TaskFilter,
+    sort: SortField,
+    order: SortOrder,
+    cursor: Option<Cursor>,
+    limit: u64,
+) -> Result<Vec<Model>, DbErr> {
+    let mut query = filter.query();
+    if let Some(cursor) = cursor {
+        let id = match order {
+            SortOrder::Asc => Column::Id.gt(cursor.id),
+            SortOrder::Desc => Column::Id.lt(cursor.id),
+        };
+        let condition = match cursor.updated_at {
+            Some(updated_at) => {
+                let key = time_key(Column::UpdatedAt);
+                let value = time_key_value(updated_at);
+                let after = match order {
+                    SortOrder::Asc => Expr::expr(key.clone()).gt(value.clone()),
+                    SortOrder::Desc => Expr::expr(key.clone()).lt(value.clone()),
+                };
+                Condition::any()
+                    .add(after)
+                    .add(Condition::all().add(Expr::expr(key).eq(value)).add(id))
+            }
+            None => Condition::all().add(id),
+        };
+        query = query.filter(condition);
+    }
+    let order = match order {
+        SortOrder::Asc => sea_orm::Order::Asc,
+        SortOrder::Desc => sea_orm::Order::Desc,
+    };
+    if sort == SortField::UpdatedAt {
+        query = query.order_by(time_key(Column::UpdatedAt), order.clone());
+    }
+    query
+        .order_by(Column::Id, order)
+        .limit(limit)
+        .all(db)
+        .await
+}
+
+/// Replaces the indexed log of a task.
+pub async fn index_log(db: &DbConn, id: i32, content: String) -> Result<(), DbErr> {
+    let backend = db.get_database_backend();
+    db.execute(sea_orm::Statement::from_sql_and_values(
+        backend,
+        "DELETE FROM task_log WHERE rowid = ?",
+        [id.into()],
+    ))
+    .await?;
+    db.execute(sea_orm::Statement::from_sql_and_values(
+        backend,
+        "INSERT INTO task_log (rowid, content) VALUES (?, ?)",
+        [id.into(), content.into()],
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Markers around the matched words in the snippets of `search_logs`.
+pub const MATCH_START: char = '\u{2}';
+pub const MATCH_END: char = '\u{3}';
+
+/// Finds the logs containing all words of the query, best matches first,
+/// returning the task ids with a snippet around the matches.
+pub async fn search_logs(db: &DbConn, query: &str, limit: u64) -> Result<Vec<(i32, String)>, DbErr> {
+    // Quote every word so the text is matched literally instead of as FTS5 syntax.
+    let query = query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ");
+    // Rows left by tasks deleted before `delete_task` removed them must not count
+    // toward the limit.
+    let sql = format!(
+        "SELECT rowid, snippet(task_log, 0, '{MATCH_START}', '{MATCH_END}', '...', 16) AS snippet \
+         FROM task_log WHERE task_log MATCH ? AND rowid IN (SELECT id FROM task) \
+         ORDER BY rank LIMIT ?"
+    );
+    let rows = db
+        .query_all(sea_orm::Statement::from_sql_and_values(
+            db.get_database_backend(),
+            sql,
+            [query.into(), limit.into()],
+        ))
+        .await?;
+    rows.iter()
+        .map(|row| Ok((row.try_get("", "rowid")?, row.try_get("", "snippet")?)))
+        .collect()
+}
+
+pub async fn tasks_by_ids(db: &DbConn, ids: &[i32]) -> Result<Vec<Model>, DbErr> {
+    Entity::find()
+        .filter(Column::Id.is_in(ids.to_vec()))
+        .all(db)
+        .await
+}
+
+/// Recipe of the command column, the SQL version of `recipe_of`: other ASCII whitespace
+/// becomes spaces, then the first word is taken.
+fn recipe_sql() -> String {
+    let mut command = "command".to_string();
+    for byte in [9, 10, 11, 12, 13] {
+        command = format!("replace({command}, char({byte}), ' ')");
+    }
+    let command = format!("trim({command})");
+    format!(
+        "CASE WHEN instr({command}, ' ') > 0 \
+        THEN substr({command}, 1, instr({command}, ' ') - 1) ELSE {command} END"
+    )
+}
+
+/// A finished task, as read for statistics.
+#[derive(Clone, Debug)]
+pub struct FinishedRun {
+    pub dir: String,
+    pub recipe: String,
+    pub status: TaskStatus,
+    /// Run time in seconds, missing for tasks finished before start times were recorded.
+    pub duration: Option<f64>,
+    /// UTC date of the end, `YYYY-MM-DD`.
+    pub date: String,
+}
+
+/// Returns the tasks finished on or after a date, optionally in one directory or of one recipe.
+pub async fn finished_runs(
+    db: &DbConn,
+    from: time::Date,
+    dir: Option<&str>,
+    recipe: Option<&str>,
+) -> Result<Vec<FinishedRun>, DbErr> {
+    let mut query = Entity::find()
+        .select_only()
+        .column(Column::Dir)
+        .column_as(Expr::cust(recipe_sql()), "recipe")
+        .column(Column::Status)
+        .column_as(
+            Expr::cust("(julianday(finished_at) - julianday(started_at)) * 86400.0"),
+            "duration",
+        )
+        .column_as(Expr::cust("substr(finished_at, 1, 10)"), "date")
+        // Dates sort as text, and a time on the day sorts after the date alone.
+        .filter(Column::FinishedAt.gte(from.to_string()))
+        .filter(Column::Status.is_in([
+            TaskStatus::Success,
+            TaskStatus::Failed,
+            TaskStatus::Interrupted,
+        ]));
+    if let Some(dir) = dir {
+        query = query.filter(Column::Dir.eq(dir));
+    }
+    if let Some(recipe) = recipe {
+        query = query.filter(Expr::cust_with_values(format!("{} = ?", recipe_sql()), [recipe]));
+    }
+    let rows = query
+        .into_tuple::<(String, String, TaskStatus, Option<f64>, String)>()
+        .all(db)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(dir, recipe, status, duration, date)| FinishedRun {
+            dir,
+            recipe,
+            status,
+            duration,
+            date,
+        })
+        .collect())
+}
+
+/// Returns the id of the last task of each directory, recipe and status finished on or
+/// after a date, with the filters of `finished_runs`.
+pub async fn last_runs(
+    db: &DbConn,
+    from: time::Date,
+    dir: Option<&str>,
+    recipe: Option<&str>,
+) -> Result<Vec<(String, String, TaskStatus, i32)>, DbErr> {
+    let mut query = Entity::find()
+        .select_only()
+        .column(Column::Dir)
+        .column_as(Expr::cust(recipe_sql()), "recipe")
+        .column(Column::Status)
+        .column_as(Column::Id.max(), "id")
+        .filter(Column::FinishedAt.gte(from.to_string()))
+        .filter(Column::Status.is_in([TaskStatus::Success, TaskStatus::Failed]));
+    if let Some(dir) = dir {
+        query = query.filter(Column::Dir.eq(dir));
+    }
+    if let Some(recipe) = recipe {
+        query = query.filter(Expr::cust_with_values(format!("{} = ?", recipe_sql()), [recipe]));
+    }
+    query
+        .group_by(Column::Dir)
+        .group_by(Expr::cust(recipe_sql()))
+        .group_by(Column::Status)
+        .into_tuple()
+        .all(db)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::AppState;
+
+    async fn finish(db: &DbConn, dir: &str, command: &str, status: TaskStatus) -> i32 {
+        let task = create_task(
+            db,
+            NewTask {
+                dir: dir.to_string(),
+                name: command.to_string(),
+                command: command.to_string(),
+                output: None,
+                submitter: "alice".to_string(),
+                idempotency_key: String::new(),
+                concurrency_key: String::new(),
+                env: HashMap::new(),
+                labels: Vec::new(),
+                trace_id: String::new(),
+                span_id: String::new(),
+                trace_flags: 0,
+            },
+        )
+        .await
+        .unwrap();
+        start_task(db, task.id, "").await.unwrap();
+        update_task(db, task.id, status).await.unwrap().id
+    }
+
+    #[tokio::test]
+    async fn statistics_take_recipes_like_recipe_of() {
+        let dir = std::env::temp_dir().join(format!("remote-task-task-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = AppState::for_test(&dir).await.conn;
+        let commands = ["build --release", "\tbuild\t--fast", "  build", "build\n-v", "deploy\r\nnow"];
+        let mut ids = Vec::new();
+        for command in commands {
+            ids.push(finish(&db, "/work", command, TaskStatus::Success).await);
+        }
+        let failed = finish(&db, "/work", " build\x0b-x", TaskStatus::Failed).await;
+        finish(&db, "/other", "build", TaskStatus::Success).await;
+
+        let today = TimeDateTimeWithTimeZone::now_utc().date();
+        let runs = finished_runs(&db, today, Some("/work"), None).await.unwrap();
+        let mut recipes = runs.iter().map(|run| run.recipe.as_str()).collect::<Vec<_>>();
+        recipes.sort();
+        assert_eq!(recipes, ["build", "build", "build", "build", "build", "deploy"]);
+        for command in commands {
+            assert!(recipes.contains(&recipe_of(command)), "{command:?}");
+        }
+        let builds = finished_runs(&db, today, Some("/work"), Some("build")).await.unwrap();
+        assert_eq!(builds.len(), 5);
+
+        let mut last = last_runs(&db, today, Some("/work"), Some("build")).await.unwrap();
+        last.sort_by_key(|(.., id)| *id);
+        assert_eq!(
+            last,
+            [
+                ("/work".to_string(), "build".to_string(), TaskStatus::Success, ids[3]),
+                ("/work".to_string(), "build".to_string(), TaskStatus::Failed, failed),
+            ]
+        );
+        let tomorrow = today.next_day().unwrap();
+        assert_eq!(last_runs(&db, tomorrow, None, None).await.unwrap(), []);
+        assert!(finished_runs(&db, tomorrow, None, None).await.unwrap().is_empty());
+    }
 }
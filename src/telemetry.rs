@@ -0,0 +1,367 @@
+use crate::task::{self, TaskStatus};
+use axum::extract::{MatchedPath, Request};
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{
+    Span as _, SpanContext, SpanId, SpanKind, Status, TraceContextExt, TraceFlags, TraceId,
+    TraceState, Tracer as _, TracerProvider as _,
+};
+use opentelemetry::{Context, KeyValue, global};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use std::env;
+use std::time::SystemTime;
+use tracing::{Instrument, field};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+const TRACER_NAME: &str = "remote-task";
+
+/// Sets up the OTLP exporter when `OTEL_EXPORTER_OTLP_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set, the other `OTEL_*` variables apply as usual.
+pub fn init() -> anyhow::Result<Option<(SdkTracerProvider, SdkTracer)>> {
+    if env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none()
+        && env::var_os("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_none()
+    {
+        return Ok(None);
+    }
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+    let mut resource = opentelemetry_sdk::Resource::builder();
+    if env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(TRACER_NAME);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    global::set_tracer_provider(provider.clone());
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = provider.tracer(TRACER_NAME);
+    Ok(Some((provider, tracer)))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Middleware running each request in a span, continuing the trace of a `traceparent` header.
+pub async fn track_request(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("other".to_string(), |path| path.as_str().to_string());
+    let span = tracing::info_span!(
+        "http_request",
+        otel.name = format!("{method} {route}"),
+        otel.kind = "server",
+        http.request.method = %method,
+        http.route = %route,
+        http.response.status_code = field::Empty,
+        // Filled in by the authentication and the handlers, for the log lines of the request.
+        user = field::Empty,
+        task_id = field::Empty,
+        recipe = field::Empty,
+        dir = field::Empty,
+    );
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+    if parent.span().span_context().is_valid() {
+        let _ = span.set_parent(parent);
+    }
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+    response
+}
+
+/// Trace and span ids and trace flags of the current span, empty when tracing is off.
+pub fn current_ids() -> (String, String, i32) {
+    let context = tracing::Span::current().context();
+    let span_context = context.span().span_context().clone();
+    if span_context.is_valid() {
+        (
+            span_context.trace_id().to_string(),
+            span_context.span_id().to_string(),
+            i32::from(span_context.trace_flags().to_u8()),
+        )
+    } else {
+        (String::new(), String::new(), 0)
+    }
+}
+
+/// Context of the request which submitted a task, with its sampling decision so the
+/// spans of a task are only recorded when its submission was.
+fn submit_context(task: &task::Model) -> Context {
+    let trace_id = TraceId::from_hex(&task.trace_id).unwrap_or(TraceId::INVALID);
+    let span_id = SpanId::from_hex(&task.span_id).unwrap_or(SpanId::INVALID);
+    let span_context = SpanContext::new(
+        trace_id,
+        span_id,
+        TraceFlags::new(task.trace_flags as u8),
+        true,
+        TraceState::default(),
+    );
+    if span_context.is_valid() {
+        Context::new().with_remote_span_context(span_context)
+    } else {
+        Context::new()
+    }
+}
+
+/// Span around the local execution of a task, in the trace of its submission.
+pub fn execution_span(task: &task::Model) -> tracing::Span {
+    let span = tracing::info_span!(
+        parent: None,
+        "execute",
+        task_id = task.id,
+        recipe = task::recipe_of(&task.command),
+        user = %task.submitter,
+        dir = %task.dir,
+    );
+    let _ = span.set_parent(submit_context(task));
+    span
+}
+
+/// Records the life of a finished task as a span with `queued` and `running` children,
+/// in the trace of the request which submitted it. Times come from the task row, so
+/// tasks run by agents or before a restart are covered as well.
+pub fn record_task(task: &task::Model) {
+    let tracer = global::tracer(TRACER_NAME);
+    let recipe = task::recipe_of(&task.command).to_string();
+    let created_at = SystemTime::from(task.created_at);
+    let finished_at = task
+        .finished_at
+        .map_or(SystemTime::now(), SystemTime::from);
+    let status = format!("{:?}", task.status).to_lowercase();
+    let attributes = vec![
+        KeyValue::new("task.id", i64::from(task.id)),
+        KeyValue::new("task.name", task.name.clone()),
+        KeyValue::new("task.recipe", recipe.clone()),
+        KeyValue::new("task.command", task.command.clone()),
+        KeyValue::new("task.dir", task.dir.clone()),
+        KeyValue::new("task.submitter", task.submitter.clone()),
+        KeyValue::new("task.worker", task.worker.clone()),
+        KeyValue::new("task.status", status),
+    ];
+    let span = tracer
+        .span_builder(format!("task {recipe}"))
+        .with_kind(SpanKind::Internal)
+        .with_start_time(created_at)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &submit_context(task));
+    let context = Context::new().with_span(span);
+    if task.status == TaskStatus::Failed {
+        context.span().set_status(Status::error("task failed"));
+    }
+    let started_at = task.started_at.map(SystemTime::from);
+    let phases = [
+        ("queued", Some(created_at), started_at.or(Some(finished_at))),
+        ("running", started_at, started_at.map(|_| finished_at)),
+    ];
+    for (name, start, end) in phases {
+        if let (Some(start), Some(end)) = (start, end) {
+            tracer
+                .span_builder(name)
+                .with_start_time(start)
+                .with_attributes([KeyValue::new("task.recipe", recipe.clone())])
+                .start_with_context(&tracer, &context)
+                .end_with_timestamp(end);
+        }
+    }
+    context.span().end_with_timestamp(finished_at);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging;
+    use crate::service::{AppState, add_task, update_task};
+    use axum::body::{Body, Bytes};
+    use axum::extract::{ConnectInfo, State};
+    use axum::routing::post;
+    use opentelemetry::trace::noop::{NoopTextMapPropagator, NoopTracerProvider};
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+    use opentelemetry_proto::tonic::common::v1::any_value;
+    use opentelemetry_proto::tonic::trace::v1::{Span, span};
+    use prost::Message;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+    use tracing_subscriber::Layer;
+    use tracing_subscriber::filter::filter_fn;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const UNSAMPLED_TRACE_ID: &str = "5bf92f3577b34da6a3ce929d0e0e4737";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+    const ENDPOINT: &str = "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT";
+
+    /// Export requests received by the collector stub.
+    type Exports = Arc<Mutex<Vec<ExportTraceServiceRequest>>>;
+
+    async fn collect(State(exports): State<Exports>, body: Bytes) {
+        let request = ExportTraceServiceRequest::decode(body).unwrap();
+        exports.lock().unwrap().push(request);
+    }
+
+    /// Starts an OTLP/HTTP collector stub, returns its traces endpoint.
+    async fn start_collector() -> (String, Exports) {
+        let exports = Exports::default();
+        let router = axum::Router::new()
+            .route("/v1/traces", post(collect))
+            .with_state(exports.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        (endpoint, exports)
+    }
+
+    /// Puts back the defaults of the global provider and propagator which `init` replaced.
+    struct ResetGlobals(SdkTracerProvider);
+
+    impl Drop for ResetGlobals {
+        fn drop(&mut self) {
+            global::set_tracer_provider(NoopTracerProvider::new());
+            global::set_text_map_propagator(NoopTextMapPropagator::new());
+            let _ = self.0.shutdown();
+            // SAFETY: no other test reads or writes the `OTEL_*` variables.
+            unsafe { env::remove_var(ENDPOINT) };
+        }
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn attribute(span: &Span, key: &str) -> Option<String> {
+        let value = span.attributes.iter().find(|attribute| attribute.key == key)?;
+        match value.value.as_ref()?.value.as_ref()? {
+            any_value::Value::StringValue(value) => Some(value.clone()),
+            any_value::Value::IntValue(value) => Some(value.to_string()),
+            value => Some(format!("{value:?}")),
+        }
+    }
+
+    /// Exported spans of a trace, other tests may finish tasks in other traces meanwhile.
+    fn exported(exports: &Exports, trace_id: &str) -> Vec<Span> {
+        exports
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|request| &request.resource_spans)
+            .flat_map(|resource| &resource.scope_spans)
+            .flat_map(|scope| &scope.spans)
+            .filter(|span| hex(&span.trace_id) == trace_id)
+            .cloned()
+            .collect()
+    }
+
+    fn span<'a>(spans: &'a [Span], name: &str) -> &'a Span {
+        spans
+            .iter()
+            .find(|span| span.name == name)
+            .unwrap_or_else(|| panic!("no {name} span in {spans:?}"))
+    }
+
+    /// Submits a task in the trace of a `traceparent` header, then runs it to the end.
+    async fn submit_and_finish(state: &AppState, trace_id: &str, flags: &str) -> task::Model {
+        let app = axum::Router::new()
+            .route("/run", post(add_task))
+            .with_state(state.clone())
+            .layer(axum::middleware::from_fn(track_request));
+        let request = Request::post("/run")
+            .header("content-type", "application/json")
+            .header("traceparent", format!("00-{trace_id}-{PARENT_ID}-{flags}"))
+            .extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))))
+            .body(Body::from(r#"{"name": "Build", "command": "build --release"}"#))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let submitted: task::Model = serde_json::from_slice(&body).unwrap();
+        task::start_task(&state.conn, submitted.id, "").await.unwrap();
+        update_task(state, submitted.id, TaskStatus::Success)
+            .await
+            .unwrap();
+        submitted
+    }
+
+    /// Sends the finished spans, on a blocking thread as the exporter waits for the collector.
+    async fn flush(provider: &SdkTracerProvider) {
+        let provider = provider.clone();
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn tasks_are_traced_with_their_submission() {
+        let dir = std::env::temp_dir().join(format!("remote-task-telemetry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = AppState::for_test(&dir).await;
+        let (endpoint, exports) = start_collector().await;
+        // SAFETY: no other test reads or writes the `OTEL_*` variables.
+        unsafe { env::set_var(ENDPOINT, &endpoint) };
+        let (provider, tracer) = init().unwrap().expect("the endpoint is set");
+        let _reset = ResetGlobals(provider.clone());
+        // Only the spans of the server itself, like the filter of `main`.
+        let targets = filter_fn(|meta| {
+            meta.target().starts_with("remote_task") && meta.target() != logging::CONTEXT_TARGET
+        });
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(targets),
+        );
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let submitted = submit_and_finish(&state, TRACE_ID, "01").await;
+        flush(&provider).await;
+        let resource = exports.lock().unwrap()[0].resource_spans[0].resource.clone().unwrap();
+        let service = resource
+            .attributes
+            .iter()
+            .find(|attribute| attribute.key == "service.name")
+            .and_then(|attribute| attribute.value.clone()?.value);
+        assert_eq!(service, Some(any_value::Value::StringValue(TRACER_NAME.to_string())));
+        let spans = exported(&exports, TRACE_ID);
+        let request = span(&spans, "POST /run");
+        assert_eq!(request.kind, span::SpanKind::Server as i32);
+        assert_eq!(hex(&request.parent_span_id), PARENT_ID);
+        assert_eq!(attribute(request, "http.route").as_deref(), Some("/run"));
+        assert_eq!(attribute(request, "recipe").as_deref(), Some("build"));
+        // The task keeps the submit request as the parent of the spans of its run.
+        assert_eq!(submitted.trace_id, TRACE_ID);
+        assert_eq!(submitted.span_id, hex(&request.span_id));
+        assert_eq!(submitted.trace_flags, 1);
+        let task = span(&spans, "task build");
+        assert_eq!(task.parent_span_id, request.span_id);
+        assert_eq!(attribute(task, "task.recipe").as_deref(), Some("build"));
+        assert_eq!(attribute(task, "task.status").as_deref(), Some("success"));
+        for name in ["queued", "running"] {
+            let phase = span(&spans, name);
+            assert_eq!(phase.parent_span_id, task.span_id);
+            assert_eq!(attribute(phase, "task.recipe").as_deref(), Some("build"));
+        }
+
+        // A submission which was not sampled is not recorded later either.
+        let submitted = submit_and_finish(&state, UNSAMPLED_TRACE_ID, "00").await;
+        flush(&provider).await;
+        assert_eq!(submitted.trace_id, UNSAMPLED_TRACE_ID);
+        assert_eq!(submitted.trace_flags, 0);
+        assert_eq!(exported(&exports, UNSAMPLED_TRACE_ID), []);
+    }
+}
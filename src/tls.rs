@@ -0,0 +1,178 @@
+use anyhow::Context;
+use axum::{
+    Extension, Router,
+    http::{HeaderMap, Uri, header},
+    middleware::AddExtension,
+    response::Redirect,
+};
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use futures::future::BoxFuture;
+use rustls::{
+    RootCertStore, ServerConfig,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use std::{env, path::PathBuf, sync::Arc, time::SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use tracing::{error, info};
+
+const RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsSettings {
+    /// Reads `TLS_CERT`, `TLS_KEY` and the optional `TLS_CLIENT_CA`.
+    /// Returns `None` when HTTPS is not configured, and fails when it is only half configured.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let var = |name| env::var(name).ok().filter(|s: &String| !s.is_empty());
+        let client_ca = var("TLS_CLIENT_CA").map(PathBuf::from);
+        match (var("TLS_CERT"), var("TLS_KEY")) {
+            (Some(cert), Some(key)) => Ok(Some(TlsSettings {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+                client_ca,
+            })),
+            (None, None) if client_ca.is_none() => Ok(None),
+            (None, None) => anyhow::bail!("TLS_CLIENT_CA needs TLS_CERT and TLS_KEY"),
+            (Some(_), None) => anyhow::bail!("TLS_CERT is set without TLS_KEY"),
+            (None, Some(_)) => anyhow::bail!("TLS_KEY is set without TLS_CERT"),
+        }
+    }
+
+    pub fn load(&self) -> anyhow::Result<ServerConfig> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("failed to read {}", self.cert.display()))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .with_context(|| format!("failed to read {}", self.key.display()))?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(client_ca)
+                    .with_context(|| format!("failed to read {}", client_ca.display()))?
+                {
+                    roots.add(cert?)?;
+                }
+                // Clients without a certificate can still authenticate with a JWT.
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .allow_unauthenticated()
+                    .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+/// Reloads the certificates whenever one of the files changes.
+pub fn watch(settings: TlsSettings, config: RustlsConfig) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut modified = settings.modified();
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            let current = settings.modified();
+            if current == modified {
+                continue;
+            }
+            modified = current;
+            match settings.load() {
+                Ok(server_config) => {
+                    config.reload_from_config(Arc::new(server_config));
+                    info!("TLS certificates reloaded");
+                }
+                Err(err) => error!("Failed to reload TLS certificates: {:#}", err),
+            }
+        }
+    })
+}
+
+/// Common name of the verified client certificate, if the client presented one.
+#[derive(Clone, Debug)]
+pub struct ClientIdentity(pub Option<String>);
+
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        ClientCertAcceptor {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, ClientIdentity>;
+    type Future = BoxFuture<'static, std::io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| common_name(cert));
+            Ok((stream, Extension(ClientIdentity(identity)).layer(service)))
+        })
+    }
+}
+
+fn common_name(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(name.to_string())
+}
+
+/// Plain HTTP listener which redirects every request to the HTTPS port.
+pub fn redirect_router(https_port: String) -> Router {
+    Router::new().fallback(move |uri: Uri, headers: HeaderMap| {
+        let https_port = https_port.clone();
+        async move {
+            let host = headers
+                .get(header::HOST)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("localhost");
+            let host = match host.rsplit_once(':') {
+                Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+                _ => host,
+            };
+            let path = uri.path_and_query().map_or("/", |p| p.as_str());
+            Redirect::permanent(&format!("https://{host}:{https_port}{path}"))
+        }
+    })
+}
@@ -0,0 +1,545 @@
+use crate::logging;
+use crate::service;
+use crate::task::{self, TaskStatus};
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{Instrument, error, info, warn};
+
+/// Header with the HMAC-SHA256 of the body, when the webhook has a secret.
+const SIGNATURE_HEADER: &str = "X-Remote-Task-Signature";
+/// Time a webhook may take to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before the first retry, doubled after every failed attempt.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+fn default_retries() -> u32 {
+    3
+}
+
+/// An outgoing webhook called when a task finishes, read from `WEBHOOKS_FILE`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Webhook {
+    /// Name in the server log, defaults to the host of the URL which may hold a token.
+    pub name: Option<String>,
+    pub url: String,
+    /// Final statuses which trigger the webhook, all of them by default.
+    #[serde(default)]
+    pub statuses: Vec<TaskStatus>,
+    /// Recipe names or glob patterns, all recipes by default.
+    #[serde(default)]
+    pub recipes: Vec<String>,
+    /// Work directories or glob patterns, all directories by default.
+    #[serde(default)]
+    pub dirs: Vec<String>,
+    /// Body to send, `{{field}}` in its strings is replaced by the field of the task.
+    /// The fields themselves are sent by default.
+    pub payload: Option<Value>,
+    /// Key of the HMAC-SHA256 signature header.
+    pub secret: Option<String>,
+    /// Attempts after the first one failed.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn matches_any(patterns: &[String], value: &str) -> bool {
+    patterns.is_empty()
+        || patterns
+            .iter()
+            .any(|pattern| wildmatch::WildMatch::new(pattern).matches(value))
+}
+
+impl Webhook {
+    fn matches(&self, task: &task::Model) -> bool {
+        (self.statuses.is_empty() || self.statuses.contains(&task.status))
+            && matches_any(&self.recipes, task::recipe_of(&task.command))
+            && matches_any(&self.dirs, &task.dir)
+    }
+
+    fn label(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            reqwest::Url::parse(&self.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_default()
+        })
+    }
+}
+
+/// Reads the webhooks of a JSON file holding a list of them.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Webhook>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let hooks: Vec<Webhook> = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    for hook in &hooks {
+        reqwest::Url::parse(&hook.url)
+            .with_context(|| format!("invalid webhook URL {}", hook.url))?;
+        if let Some(status) = hook.statuses.iter().find(|status| !status.is_finished()) {
+            anyhow::bail!(
+                "webhook {} waits for {:?}, which is not a final status",
+                hook.label(),
+                status
+            );
+        }
+    }
+    Ok(hooks)
+}
+
+/// Fields of a finished task available to payloads, links start with `PUBLIC_URL`.
+async fn fields(task: &task::Model, public_url: &str, output_dir: &Path) -> Map<String, Value> {
+    let mut package_url = None;
+    // Only outputs which `/package` would serve, like the artifact of `GET /task`.
+    if let Some(output) = &task.output
+        && let Ok(path) = service::output_path(output_dir, output)
+        && tokio::fs::metadata(path)
+            .await
+            .is_ok_and(|metadata| metadata.is_file())
+    {
+        package_url = Some(format!("{public_url}{}", service::package_url(output)));
+    }
+    let timestamp = |time: Option<time::OffsetDateTime>| {
+        time.and_then(|time| {
+            time.format(&time::format_description::well_known::Rfc3339)
+                .ok()
+        })
+    };
+    let mut fields = Map::new();
+    fields.insert("id".into(), task.id.into());
+    fields.insert("name".into(), task.name.clone().into());
+    fields.insert("command".into(), task.command.clone().into());
+    fields.insert("recipe".into(), task::recipe_of(&task.command).into());
+    fields.insert("status".into(), format!("{:?}", task.status).into());
+    fields.insert("dir".into(), task.dir.clone().into());
+    fields.insert("submitter".into(), task.submitter.clone().into());
+    fields.insert("worker".into(), task.worker.clone().into());
+    fields.insert("started_at".into(), timestamp(task.started_at).into());
+    fields.insert("finished_at".into(), timestamp(task.finished_at).into());
+    let duration = task.duration().map(|duration| duration.as_seconds_f64());
+    fields.insert("duration_secs".into(), duration.into());
+    fields.insert(
+        "task_url".into(),
+        format!("{public_url}/task/{}", task.id).into(),
+    );
+    let log_url = format!("{public_url}/logs/{}/{}.log", task.month(), task.id);
+    fields.insert("log_url".into(), log_url.into());
+    fields.insert("package_url".into(), package_url.into());
+    fields
+}
+
+/// Fills in the fields of a payload template. A string which is only a placeholder takes
+/// the value of the field as is, so numbers stay numbers.
+fn render(template: &Value, fields: &Map<String, Value>) -> Value {
+    match template {
+        Value::String(text) => {
+            if let Some(name) = text
+                .strip_prefix("{{")
+                .and_then(|rest| rest.strip_suffix("}}"))
+                && let Some(value) = fields.get(name)
+            {
+                return value.clone();
+            }
+            let mut text = text.clone();
+            for (name, value) in fields {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    Value::Null => String::new(),
+                    value => value.to_string(),
+                };
+                text = text.replace(&format!("{{{{{name}}}}}"), &value);
+            }
+            Value::String(text)
+        }
+        Value::Array(items) => {
+            Value::Array(items.iter().map(|item| render(item, fields)).collect())
+        }
+        Value::Object(object) => Value::Object(
+            object
+                .iter()
+                .map(|(key, value)| (key.clone(), render(value, fields)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+/// Value of the signature header, `sha256=` and the hex HMAC of the body.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    let mut signature = "sha256=".to_string();
+    for byte in mac.finalize().into_bytes() {
+        let _ = write!(signature, "{byte:02x}");
+    }
+    signature
+}
+
+pub struct Webhooks {
+    hooks: Vec<Webhook>,
+    client: reqwest::Client,
+    public_url: String,
+    output_dir: PathBuf,
+    first_backoff: Duration,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<Webhook>, public_url: &str, output_dir: PathBuf) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+        Ok(Webhooks {
+            hooks,
+            client,
+            public_url: public_url.trim_end_matches('/').to_string(),
+            output_dir,
+            first_backoff: FIRST_BACKOFF,
+        })
+    }
+
+    /// Posts the body, retrying with growing waits on network errors, server errors,
+    /// `408` and `429`. Other client errors mean the receiver will not take the event.
+    async fn deliver(&self, hook: &Webhook, body: Vec<u8>) {
+        let name = hook.label();
+        let mut backoff = self.first_backoff;
+        for attempt in 0..=hook.retries {
+            if attempt > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            let mut request = self
+                .client
+                .post(&hook.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(secret) = &hook.secret {
+                request = request.header(SIGNATURE_HEADER, sign(secret, &body));
+            }
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    info!("Webhook {} delivered", name);
+                    return;
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    let retry = status.is_server_error()
+                        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+                        || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                    if !retry {
+                        error!("Webhook {} rejected the event: {}", name, status);
+                        return;
+                    }
+                    warn!("Webhook {} answered {}", name, status);
+                }
+                Err(err) => warn!("Webhook {} failed: {}", name, err),
+            }
+        }
+        error!(
+            "Webhook {} gave up after {} attempts",
+            name,
+            hook.retries + 1
+        );
+    }
+}
+
+/// Calls the matching webhooks for every task which reaches a final status,
+/// as sent by `update_task`.
+pub async fn dispatch(webhooks: Arc<Webhooks>, mut finished: mpsc::UnboundedReceiver<task::Model>) {
+    while let Some(task) = finished.recv().await {
+        let fields = fields(&task, &webhooks.public_url, &webhooks.output_dir).await;
+        for index in 0..webhooks.hooks.len() {
+            let hook = &webhooks.hooks[index];
+            if !hook.matches(&task) {
+                continue;
+            }
+            let payload = match &hook.payload {
+                Some(template) => render(template, &fields),
+                None => Value::Object(fields.clone()),
+            };
+            let body = payload.to_string().into_bytes();
+            let webhooks = webhooks.clone();
+            tokio::spawn(
+                async move { webhooks.deliver(&webhooks.hooks[index], body).await }
+                    .instrument(logging::task_span(&task)),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::{AppState, update_task};
+    use axum::{Router, extract::State, http::HeaderMap, routing::post};
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    /// Request received by the stub: when, the signature header and the body.
+    type Received = (Instant, Option<String>, Value);
+
+    #[derive(Clone, Default)]
+    struct Stub {
+        /// Statuses to answer in order, then `200`.
+        statuses: Arc<Mutex<Vec<u16>>>,
+        received: Arc<Mutex<Vec<Received>>>,
+    }
+
+    async fn receive(State(stub): State<Stub>, headers: HeaderMap, body: String) -> axum::http::StatusCode {
+        let signature = headers
+            .get(SIGNATURE_HEADER)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = serde_json::from_str(&body).unwrap();
+        stub.received.lock().unwrap().push((Instant::now(), signature, body));
+        let mut statuses = stub.statuses.lock().unwrap();
+        let status = if statuses.is_empty() { 200 } else { statuses.remove(0) };
+        axum::http::StatusCode::from_u16(status).unwrap()
+    }
+
+    /// Starts a receiver answering `statuses`, returns its URL.
+    async fn start_stub(statuses: &[u16]) -> (String, Stub) {
+        let stub = Stub {
+            statuses: Arc::new(Mutex::new(statuses.to_vec())),
+            ..Default::default()
+        };
+        let router = Router::new()
+            .route("/hook", post(receive))
+            .with_state(stub.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        (url, stub)
+    }
+
+    fn hook(url: &str) -> Webhook {
+        serde_json::from_value(serde_json::json!({ "url": url })).unwrap()
+    }
+
+    fn webhooks(hooks: Vec<Webhook>, output_dir: &Path) -> Webhooks {
+        let mut webhooks =
+            Webhooks::new(hooks, "https://build.example.com/", output_dir.to_path_buf()).unwrap();
+        webhooks.first_backoff = Duration::from_millis(50);
+        webhooks
+    }
+
+    fn task(command: &str, dir: &str, status: TaskStatus) -> task::Model {
+        let mut task = task::Model::for_test(7);
+        task.name = command.to_string();
+        task.dir = dir.to_string();
+        task.command = command.to_string();
+        task.status = status;
+        task.started_at = Some(task.created_at - time::Duration::seconds(90));
+        task.finished_at = Some(task.created_at);
+        task
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "remote-task-webhooks-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn wait_for(stub: &Stub, count: usize) -> Vec<Received> {
+        for _ in 0..100 {
+            if stub.received.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        stub.received.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn matches_statuses_recipes_and_dirs() {
+        let filtered: Webhook = serde_json::from_value(serde_json::json!({
+            "url": "http://localhost/hook",
+            "statuses": ["Failed", "Interrupted"],
+            "recipes": ["release*", "deploy"],
+            "dirs": ["/srv/*"],
+        }))
+        .unwrap();
+        assert!(filtered.matches(&task("release-linux --fast", "/srv/app", TaskStatus::Failed)));
+        assert!(filtered.matches(&task("deploy", "/srv/web", TaskStatus::Interrupted)));
+        assert!(!filtered.matches(&task("release", "/srv/app", TaskStatus::Success)));
+        assert!(!filtered.matches(&task("deploy-staging", "/srv/app", TaskStatus::Failed)));
+        assert!(!filtered.matches(&task("release", "/home/app", TaskStatus::Failed)));
+
+        let every_task = hook("http://localhost/hook");
+        for status in [TaskStatus::Success, TaskStatus::Failed, TaskStatus::Interrupted] {
+            assert!(every_task.matches(&task("anything", "/anywhere", status)));
+        }
+    }
+
+    #[tokio::test]
+    async fn renders_fields_with_an_encoded_package_url() {
+        let dir = test_dir("render");
+        std::fs::create_dir_all(dir.join("release 1")).unwrap();
+        std::fs::write(dir.join("release 1/app#2?.zip"), "zip").unwrap();
+        let mut task = task("release --fast", "/srv/app", TaskStatus::Success);
+        task.output = Some("release 1/app#2?.zip".to_string());
+
+        let fields = fields(&task, "https://build.example.com", &dir).await;
+        assert_eq!(
+            fields["package_url"],
+            "https://build.example.com/package/release%201/app%232%3F.zip"
+        );
+        assert_eq!(fields["task_url"], "https://build.example.com/task/7");
+        assert_eq!(fields["recipe"], "release");
+        assert_eq!(fields["duration_secs"], 90.0);
+
+        let template = serde_json::json!({
+            "text": "{{recipe}} of {{submitter}} ended with {{status}}: {{package_url}}",
+            "id": "{{id}}",
+            "links": ["{{log_url}}"],
+            "missing": "{{unknown}}",
+            "count": 1,
+        });
+        let expected_log = format!("https://build.example.com/logs/{}/7.log", task.month());
+        assert_eq!(
+            render(&template, &fields),
+            serde_json::json!({
+                "text": "release of alice ended with Success: https://build.example.com/package/release%201/app%232%3F.zip",
+                "id": 7,
+                "links": [expected_log],
+                "missing": "{{unknown}}",
+                "count": 1,
+            })
+        );
+
+        task.output = Some("release 1/missing.zip".to_string());
+        let fields = super::fields(&task, "https://build.example.com", &dir).await;
+        assert_eq!(fields["package_url"], Value::Null);
+        assert_eq!(
+            render(&serde_json::json!("link: {{package_url}}"), &fields),
+            "link: "
+        );
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[tokio::test]
+    async fn dispatches_finished_tasks_to_matching_hooks() {
+        let dir = test_dir("dispatch");
+        let (url, stub) = start_stub(&[]).await;
+        let (other_url, other) = start_stub(&[]).await;
+        let mut signed = hook(&url);
+        signed.secret = Some("s3cret".to_string());
+        signed.payload = Some(serde_json::json!({"text": "{{name}} {{status}}", "id": "{{id}}"}));
+        let mut failures = hook(&other_url);
+        failures.statuses = vec![TaskStatus::Failed];
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(dispatch(
+            Arc::new(webhooks(vec![signed, failures], &dir)),
+            receiver,
+        ));
+
+        sender.send(task("build", "/srv/app", TaskStatus::Success)).unwrap();
+        let received = wait_for(&stub, 1).await;
+        let (_, signature, body) = &received[0];
+        assert_eq!(body, &serde_json::json!({"text": "build Success", "id": 7}));
+        assert_eq!(
+            signature.as_deref(),
+            Some(sign("s3cret", body.to_string().as_bytes()).as_str())
+        );
+
+        sender.send(task("test", "/srv/app", TaskStatus::Failed)).unwrap();
+        let received = wait_for(&other, 1).await;
+        assert_eq!(received.len(), 1);
+        let (_, signature, body) = &received[0];
+        assert_eq!(signature, &None);
+        assert_eq!(body["status"], "Failed");
+        assert_eq!(body["package_url"], Value::Null);
+        assert_eq!(wait_for(&stub, 2).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn update_task_sends_finished_tasks_to_the_webhooks() {
+        let dir = test_dir("update");
+        std::fs::create_dir_all(dir.join("release 1")).unwrap();
+        std::fs::write(dir.join("release 1/app.zip"), "zip").unwrap();
+        let mut state = AppState::for_test(&dir).await;
+        let (url, stub) = start_stub(&[]).await;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        state.finished = Some(sender);
+        tokio::spawn(dispatch(Arc::new(webhooks(vec![hook(&url)], &dir)), receiver));
+
+        // The second output exists, but outside of the output directory.
+        let escaping = format!("../{}/tasks.db", dir.file_name().unwrap().to_string_lossy());
+        for output in ["release 1/app.zip", escaping.as_str()] {
+            let task = task::create_task(
+                &state.conn,
+                task::NewTask {
+                    dir: dir.to_string_lossy().to_string(),
+                    name: "Release".to_string(),
+                    command: "release".to_string(),
+                    output: Some(output.to_string()),
+                    submitter: "alice".to_string(),
+                    idempotency_key: String::new(),
+                    concurrency_key: String::new(),
+                    env: Default::default(),
+                    labels: Vec::new(),
+                    trace_id: String::new(),
+                    span_id: String::new(),
+                    trace_flags: 0,
+                },
+            )
+            .await
+            .unwrap();
+            update_task(&state, task.id, TaskStatus::Running).await.unwrap();
+            update_task(&state, task.id, TaskStatus::Success).await.unwrap();
+        }
+
+        let received = wait_for(&stub, 2).await;
+        assert_eq!(received.len(), 2);
+        let body = |id: i32| &received.iter().find(|(_, _, body)| body["id"] == id).unwrap().2;
+        assert_eq!(body(1)["status"], "Success");
+        assert_eq!(
+            body(1)["package_url"],
+            "https://build.example.com/package/release%201/app.zip"
+        );
+        assert_eq!(body(2)["package_url"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_with_growing_waits() {
+        let dir = test_dir("retry");
+        let (url, stub) = start_stub(&[500, 503, 429]).await;
+        let webhooks = webhooks(vec![hook(&url)], &dir);
+        webhooks.deliver(&webhooks.hooks[0], b"{}".to_vec()).await;
+        let times: Vec<Instant> = stub.received.lock().unwrap().iter().map(|received| received.0).collect();
+        assert_eq!(times.len(), 4);
+        for (index, wait) in [50, 100, 200].into_iter().enumerate() {
+            assert!(times[index + 1] - times[index] >= Duration::from_millis(wait));
+        }
+
+        let (url, stub) = start_stub(&[500, 500, 500, 500, 500]).await;
+        let mut hook = hook(&url);
+        hook.retries = 2;
+        webhooks.deliver(&hook, b"{}".to_vec()).await;
+        assert_eq!(stub.received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_client_errors() {
+        let dir = test_dir("reject");
+        let (url, stub) = start_stub(&[404]).await;
+        let webhooks = webhooks(vec![hook(&url)], &dir);
+        webhooks.deliver(&webhooks.hooks[0], b"{}".to_vec()).await;
+        assert_eq!(stub.received.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,334 @@
+use crate::task;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How long a long poll of an agent waits for a task.
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Agents which have not polled for this long are considered gone.
+pub const WORKER_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Sent by an agent when it starts, and again after the server forgot it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Registration {
+    pub name: String,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    pub dirs: Vec<WorkerDir>,
+    /// Tasks the agent runs at the same time.
+    pub slots: usize,
+    /// Tasks the agent is still running when it registers again, which are kept running.
+    #[serde(default)]
+    pub running: Vec<i32>,
+}
+
+/// A work directory on the agent machine with its recipes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkerDir {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub recipes: Vec<String>,
+    /// Labels of the directory, added to the labels of the agent.
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+/// Splits a label like `os=windows` into key and value, a bare `name` means `name=true`.
+fn split_label(label: &str) -> (&str, &str) {
+    match label.split_once('=') {
+        Some((key, value)) => (key.trim(), value.trim()),
+        None => (label.trim(), "true"),
+    }
+}
+
+/// Parses comma separated labels, like `os=windows,gpu`.
+pub fn parse_labels(labels: &str) -> Vec<String> {
+    labels
+        .split(',')
+        .map(str::trim)
+        .filter(|label| !label.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn is_label(label: &str) -> bool {
+    let (key, value) = split_label(label);
+    !key.is_empty() && !value.is_empty()
+}
+
+/// Whether labels satisfy all requirements, a missing label counts as `false`.
+pub fn labels_match<'a>(
+    requirements: &[String],
+    labels: impl Iterator<Item = &'a String> + Clone,
+) -> bool {
+    requirements.iter().all(|requirement| {
+        let (key, value) = split_label(requirement);
+        let actual = labels
+            .clone()
+            .map(|label| split_label(label))
+            .find(|(k, _)| *k == key)
+            .map_or("false", |(_, v)| v);
+        actual == value
+    })
+}
+
+/// A task handed to an agent, with its environment including secrets.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AgentJob {
+    pub task: task::Model,
+    pub env: Vec<(String, String)>,
+    /// Values of `env` to redact from the log.
+    pub secrets: Vec<String>,
+}
+
+/// Status of a task sent by the agent running it. `Running` confirms that the agent
+/// received the task, the others are final.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub status: task::TaskStatus,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkerQuery {
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct WorkerStatus {
+    #[serde(flatten)]
+    pub registration: Registration,
+    pub running: Vec<i32>,
+    pub last_seen_secs: u64,
+}
+
+struct Worker {
+    registration: Registration,
+    last_seen: Instant,
+    /// Concurrency keys of the tasks running on the worker.
+    running: HashMap<i32, String>,
+    /// Tasks dispatched to the worker which it has not confirmed yet.
+    assigned: VecDeque<i32>,
+    notify: Arc<Notify>,
+}
+
+impl Worker {
+    fn serves(&self, dir: &Path) -> bool {
+        self.registration.dirs.iter().any(|d| d.path == dir)
+    }
+
+    /// Whether the worker serves the directory with the required labels.
+    fn matches(&self, dir: &Path, requirements: &[String]) -> bool {
+        self.registration.dirs.iter().any(|d| {
+            d.path == dir
+                && labels_match(
+                    requirements,
+                    self.registration.labels.iter().chain(&d.labels),
+                )
+        })
+    }
+
+    fn is_free(&self) -> bool {
+        self.running.len() < self.registration.slots.max(1)
+    }
+}
+
+/// Registry of the agents which run tasks for the server.
+#[derive(Default)]
+pub struct Workers {
+    workers: Mutex<HashMap<String, Worker>>,
+}
+
+impl Workers {
+    /// Adds or replaces a worker, which still runs the `running` tasks with their
+    /// concurrency keys.
+    pub fn register(&self, registration: Registration, running: HashMap<i32, String>) {
+        let mut workers = self.workers.lock().unwrap();
+        let worker = Worker {
+            registration: registration.clone(),
+            last_seen: Instant::now(),
+            running,
+            assigned: VecDeque::new(),
+            notify: Arc::new(Notify::new()),
+        };
+        workers.insert(registration.name, worker);
+    }
+
+    /// Concurrency keys of the tasks running on all workers.
+    pub fn running(&self) -> HashMap<i32, String> {
+        let workers = self.workers.lock().unwrap();
+        workers
+            .values()
+            .flat_map(|worker| worker.running.clone())
+            .collect()
+    }
+
+    pub fn serves(&self, dir: &Path) -> bool {
+        let workers = self.workers.lock().unwrap();
+        workers.values().any(|worker| worker.serves(dir))
+    }
+
+    /// Whether any worker, busy or not, could run a task in the directory.
+    pub fn can_run(&self, dir: &Path, requirements: &[String]) -> bool {
+        let workers = self.workers.lock().unwrap();
+        workers
+            .values()
+            .any(|worker| worker.matches(dir, requirements))
+    }
+
+    /// Dispatches a task to a free worker serving its directory with the required labels,
+    /// returning the worker name.
+    pub fn assign(&self, id: i32, dir: &Path, key: &str, requirements: &[String]) -> Option<String> {
+        let mut workers = self.workers.lock().unwrap();
+        let worker = workers
+            .values_mut()
+            .filter(|worker| worker.matches(dir, requirements) && worker.is_free())
+            .min_by_key(|worker| worker.running.len())?;
+        worker.running.insert(id, key.to_string());
+        worker.assigned.push_back(id);
+        worker.notify.notify_one();
+        Some(worker.registration.name.clone())
+    }
+
+    /// Returns the next task dispatched to a worker, or the notification to wait for one.
+    /// The task is returned by every poll until the worker confirms it, so a response lost
+    /// on the way does not lose the task. Returns `None` when the worker is not registered.
+    pub fn poll(&self, name: &str) -> Option<Result<i32, Arc<Notify>>> {
+        let mut workers = self.workers.lock().unwrap();
+        let worker = workers.get_mut(name)?;
+        worker.last_seen = Instant::now();
+        Some(worker.assigned.front().copied().ok_or(worker.notify.clone()))
+    }
+
+    /// Records that the worker received a task.
+    pub fn confirm(&self, name: &str, id: i32) {
+        let mut workers = self.workers.lock().unwrap();
+        if let Some(worker) = workers.get_mut(name) {
+            worker.assigned.retain(|assigned| *assigned != id);
+        }
+    }
+
+    pub fn finish(&self, name: &str, id: i32) {
+        let mut workers = self.workers.lock().unwrap();
+        if let Some(worker) = workers.get_mut(name) {
+            worker.running.remove(&id);
+            worker.assigned.retain(|assigned| *assigned != id);
+        }
+    }
+
+    /// Removes the workers which stopped polling, returning the tasks they were running.
+    pub fn expire(&self) -> Vec<i32> {
+        let mut workers = self.workers.lock().unwrap();
+        let mut lost = Vec::new();
+        workers.retain(|_, worker| {
+            let alive = worker.last_seen.elapsed() < WORKER_TIMEOUT;
+            if !alive {
+                lost.extend(worker.running.keys());
+            }
+            alive
+        });
+        lost
+    }
+
+    /// When the first worker expires if it stops polling, `None` without workers.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        let workers = self.workers.lock().unwrap();
+        workers
+            .values()
+            .map(|worker| worker.last_seen + WORKER_TIMEOUT)
+            .min()
+    }
+
+    pub fn recipes(&self, dir: &Path) -> Option<Vec<String>> {
+        let workers = self.workers.lock().unwrap();
+        workers
+            .values()
+            .flat_map(|worker| &worker.registration.dirs)
+            .find(|d| d.path == dir)
+            .map(|d| d.recipes.clone())
+    }
+
+    pub fn dirs(&self) -> Vec<PathBuf> {
+        let workers = self.workers.lock().unwrap();
+        let mut dirs = workers
+            .values()
+            .flat_map(|worker| worker.registration.dirs.iter().map(|d| d.path.clone()))
+            .collect::<Vec<_>>();
+        dirs.sort();
+        dirs.dedup();
+        dirs
+    }
+
+    pub fn list(&self) -> Vec<WorkerStatus> {
+        let workers = self.workers.lock().unwrap();
+        let mut list = workers
+            .values()
+            .map(|worker| WorkerStatus {
+                registration: worker.registration.clone(),
+                running: worker.running.keys().copied().collect(),
+                last_seen_secs: worker.last_seen.elapsed().as_secs(),
+            })
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| a.registration.name.cmp(&b.registration.name));
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(name: &str) -> Registration {
+        Registration {
+            name: name.to_string(),
+            labels: Vec::new(),
+            dirs: vec![WorkerDir {
+                path: PathBuf::from("/work"),
+                recipes: Vec::new(),
+                labels: Vec::new(),
+            }],
+            slots: 2,
+            running: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn expiry_follows_the_last_poll() {
+        let workers = Workers::default();
+        assert_eq!(workers.next_expiry(), None);
+        let before = Instant::now();
+        workers.register(registration("a1"), HashMap::new());
+        let expiry = workers.next_expiry().unwrap();
+        assert!(expiry >= before + WORKER_TIMEOUT && expiry <= Instant::now() + WORKER_TIMEOUT);
+        assert!(workers.expire().is_empty());
+    }
+
+    #[test]
+    fn polls_return_a_task_until_it_is_confirmed() {
+        let workers = Workers::default();
+        workers.register(registration("a1"), HashMap::new());
+        let dir = Path::new("/work");
+        assert_eq!(workers.assign(1, dir, "k1", &[]).as_deref(), Some("a1"));
+        assert_eq!(workers.assign(2, dir, "k2", &[]).as_deref(), Some("a1"));
+
+        assert_eq!(workers.poll("a1").unwrap().ok(), Some(1));
+        assert_eq!(workers.poll("a1").unwrap().ok(), Some(1));
+        workers.confirm("a1", 1);
+        assert_eq!(workers.poll("a1").unwrap().ok(), Some(2));
+        workers.finish("a1", 2);
+        assert!(workers.poll("a1").unwrap().is_err());
+        assert_eq!(workers.running().keys().collect::<Vec<_>>(), [&1]);
+        assert!(workers.poll("a2").is_none());
+    }
+
+    #[test]
+    fn registering_again_keeps_the_running_tasks() {
+        let workers = Workers::default();
+        let running = HashMap::from([(7, "key".to_string())]);
+        workers.register(registration("a1"), running.clone());
+        assert_eq!(workers.running(), running);
+        assert!(workers.poll("a1").unwrap().is_err());
+    }
+}
@@ -46,7 +46,7 @@ fn Main() -> Element {
             .send()
             .await
             .unwrap()
-            .json::<(Vec<task::Task>, i32)>()
+            .json::<task::TaskPage>()
             .await
     });
 
@@ -74,7 +74,7 @@ pub struct DirInfo {
 #[component]
 fn Form(
     page: Signal<i32>,
-    resource: Resource<Result<(Vec<task::Task>, i32), reqwest::Error>>,
+    resource: Resource<Result<task::TaskPage, reqwest::Error>>,
 ) -> Element {
     let recipes = use_resource(move || async move {
         let origin = window().unwrap().location().origin().unwrap();
@@ -159,13 +159,13 @@ fn Form(
 #[component]
 fn List(
     page: Signal<i32>,
-    resource: Resource<Result<(Vec<task::Task>, i32), reqwest::Error>>,
+    resource: Resource<Result<task::TaskPage, reqwest::Error>>,
     task_updates: Signal<HashMap<i32, String>>,
 ) -> Element {
     let updates = task_updates();
 
     match &*resource.read_unchecked() {
-        Some(Ok((tasks, pages))) => {
+        Some(Ok(task::TaskPage { tasks, pages })) => {
             // Create updated task list with SSE status updates
             let updated_tasks: Vec<task::Task> = tasks
                 .iter()
@@ -209,7 +209,7 @@ fn List(
                                             }
                                         }
                                     }
-                                    td { "{task.status_emoji()}" }
+                                    td { title: "{task.waiting_reason}", "{task.status_emoji()}" }
                                     td {
                                         if task.status == "Pending" {
                                             button {
@@ -222,7 +222,7 @@ fn List(
                                                 },
                                                 "Cancel"
                                             }
-                                        } else if task.status == "Failed" && task.can_rerun() {
+                                        } else if (task.status == "Failed" || task.status == "Interrupted") && task.can_rerun() {
                                             button {
                                                 class: "outline secondary",
                                                 onclick: move |_| async move {
@@ -263,7 +263,7 @@ fn List(
                                 button {
                                     class: "outline secondary contrast",
                                     onclick: move |_| page.set(page() + 1),
-                                    disabled: page() == *pages,
+                                    disabled: page() as u64 >= *pages,
                                     "Next"
                                 }
                             }
@@ -8,10 +8,19 @@ pub struct Task {
     // pub command: String,
     pub output: Option<String>,
     pub status: String,
+    #[serde(default)]
+    pub waiting_reason: String,
     pub created_at: String,
     // pub updated_at: String,
 }
 
+/// Response of `GET /list/{page}`.
+#[derive(Clone, Deserialize)]
+pub struct TaskPage {
+    pub tasks: Vec<Task>,
+    pub pages: u64,
+}
+
 impl Task {
     pub fn month(&self) -> String {
         self.created_at[..7].to_string()
@@ -27,6 +36,7 @@ impl Task {
             "Running" => "🏗️",
             "Success" => "✅",
             "Failed" => "❌",
+            "Interrupted" => "⛔",
             _ => "❓",
         }
     }